@@ -1,5 +1,13 @@
-use chprog_lib::ChProg;
-use clap::Parser;
+use chprog_lib::protocol::ChipConfig;
+use chprog_lib::sequence::{Bootloader, UnknownBootloaderStr};
+use chprog_lib::ChProgBuilder;
+use clap::builder::TypedValueParser;
+use clap::error::ErrorKind;
+use clap::{Arg, Command, CommandFactory, Parser};
+use clap_complete::Shell;
+use serde::Serialize;
+use std::ffi::OsStr;
+use std::path::PathBuf;
 
 /// CH55x UART serial bootloader flash tool
 #[derive(Parser, Debug)]
@@ -10,9 +18,9 @@ use clap::Parser;
     long_about = "ChProg is an application for firmware operations using UART with WCH CH55x series microcontrollers"
 )]
 struct Args {
-    /// Serial port name to use
+    /// Serial port name to use, detected if only one chip is connected
     #[clap(short, long, value_parser)]
-    port: String,
+    port: Option<String>,
 
     /// Write file to flash, verify and exit the bootloader
     #[clap(short, long, action)]
@@ -22,6 +30,10 @@ struct Args {
     #[clap(short, long, action)]
     verify: bool,
 
+    /// Check that Intel HEX file is well-formed and fits into chip flash
+    #[clap(long, action)]
+    validate: bool,
+
     /// Detect chip and bootloader version
     #[clap(short, long, action)]
     detect: bool,
@@ -34,61 +46,337 @@ struct Args {
     #[clap(short, long, action)]
     reset: bool,
 
+    /// Do not touch reset lines, chip is already in bootloader mode
+    #[clap(long, action, conflicts_with = "reset")]
+    no_reset: bool,
+
+    /// Detect only specified bootloader version [possible values: v1, v2]
+    #[clap(long, value_parser = BootloaderParser)]
+    chip_family: Option<Bootloader>,
+
+    /// Serial port baud rate [possible values: 9600, 19200, 38400, 57600, 115200, 230400]
+    #[clap(long, value_parser = parse_baud_rate, default_value = "57600", value_name = "RATE")]
+    baud: serial::BaudRate,
+
+    /// Detect bootloader up to n times right after opening port, resetting chip before each attempt
+    #[clap(long, value_parser, default_value = "1", value_name = "N")]
+    connect_retries: u32,
+
+    /// Write chip configuration word (CFG_WORD) given in hex
+    #[clap(long, value_parser = parse_hex_u32, value_name = "HEX")]
+    write_config: Option<u32>,
+
     /// Target file to be flashed
-    #[clap(short, long, action)]
-    file: Option<String>,
+    #[clap(short, long, value_parser)]
+    file: Option<PathBuf>,
+
+    /// Print operation result as JSON object instead of text messages
+    #[clap(long, action)]
+    json: bool,
+
+    /// Print shell completions and exit
+    #[clap(long, value_enum)]
+    generate_completions: Option<Shell>,
+}
+
+/// Operation result printed with --json
+#[derive(Serialize, Debug, Default)]
+struct CliOutput {
+    chip_id: Option<String>,
+    bootloader: Option<String>,
+    operation: &'static str,
+    success: bool,
+    error: Option<String>,
+}
+
+impl CliOutput {
+    /// Record failure of current operation
+    fn fail(&mut self, json: bool, message: String) {
+        if !json {
+            println!("ERROR: {}", message);
+        }
+        self.error = Some(message);
+    }
+}
+
+/// Parse hex number with optional 0x prefix
+fn parse_hex_u32(value: &str) -> Result<u32, String> {
+    let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(digits, 16).map_err(|err| err.to_string())
+}
+
+/// Parse serial baud rate supported by CH55x bootloader
+fn parse_baud_rate(value: &str) -> Result<serial::BaudRate, String> {
+    match value.parse::<u32>() {
+        Ok(9600) => Ok(serial::Baud9600),
+        Ok(19200) => Ok(serial::Baud19200),
+        Ok(38400) => Ok(serial::Baud38400),
+        Ok(57600) => Ok(serial::Baud57600),
+        Ok(115200) => Ok(serial::Baud115200),
+        Ok(230400) => Ok(serial::BaudOther(230400)),
+        _ => Err(format!("unsupported baud rate {}", value)),
+    }
+}
+
+/// Bootloader version argument parser
+#[derive(Clone)]
+struct BootloaderParser;
+
+impl TypedValueParser for BootloaderParser {
+    type Value = Bootloader;
+
+    fn parse_ref(
+        &self,
+        cmd: &Command,
+        _arg: Option<&Arg>,
+        value: &OsStr,
+    ) -> Result<Self::Value, clap::Error> {
+        let value = value.to_string_lossy();
+        value
+            .parse()
+            .map_err(|err: UnknownBootloaderStr| cmd.clone().error(ErrorKind::InvalidValue, err))
+    }
 }
 
 fn main() {
+    // Show library messages, RUST_LOG overrides the level
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format_timestamp(None)
+        .format_target(false)
+        .init();
+
     let args = Args::parse();
 
+    if let Some(shell) = args.generate_completions {
+        // Print completions
+        clap_complete::generate(
+            shell,
+            &mut Args::command(),
+            env!("CARGO_BIN_NAME"),
+            &mut std::io::stdout(),
+        );
+        return;
+    }
+
+    let json = args.json;
+    let mut output = CliOutput::default();
+    run(args, &mut output);
+
+    if json {
+        output.success = output.error.is_none();
+        match serde_json::to_string(&output) {
+            Ok(text) => println!("{}", text),
+            Err(err) => eprintln!("ERROR: Cannot serialize output: {}", err),
+        }
+    }
+}
+
+/// Run operations requested by *args*, collecting result into *output*
+fn run(args: Args, output: &mut CliOutput) {
+    let json = args.json;
+
+    // Text messages are suppressed for JSON output
+    let say = |message: &str| {
+        if !json {
+            println!("{}", message);
+        }
+    };
+
+    // Use specified port or lookup the only connected chip
+    output.operation = "connect";
+    let port = match args.port {
+        Some(port) => port,
+        None => {
+            let mut ports = chprog_lib::serial::list_ch55x_ports();
+            if ports.len() != 1 {
+                output.fail(
+                    json,
+                    format!(
+                        "Cannot select port, found {} candidates, use --port",
+                        ports.len()
+                    ),
+                );
+                return;
+            }
+
+            let (port, chip_family) = ports.remove(0);
+            say(&format!("Using port: {} ({:?})", port, chip_family));
+            port
+        }
+    };
+
+    let mut builder = ChProgBuilder::default()
+        .port(&port)
+        .baud_rate(args.baud)
+        .no_reset(args.no_reset)
+        .connect_retries(args.connect_retries);
+    if let Some(bootloader) = args.chip_family {
+        // Restrict detection
+        builder = builder.preferred_bootloader(bootloader);
+    }
+
     // Try to open serial port
-    if let Ok(mut chprog) = ChProg::new(args.port.clone()) {
-        if args.reset {
-            // Reset
-            println!("Resetting");
-            chprog.reset();
+    let mut chprog = match builder.build() {
+        Ok(chprog) => chprog,
+        Err(err) => {
+            output.fail(json, format!("Cannot connect to port {}: {}", port, err));
+            return;
         }
+    };
 
-        if args.detect {
-            // Detect
-            println!("Detecting");
-            if let Err(err) = chprog.detect() {
-                println!("ERROR: Detecting failed: {}", err);
+    if args.reset {
+        // Reset
+        output.operation = "reset";
+        say("Resetting");
+        chprog.reset();
+    }
+
+    // Config read while detecting, kept for config write
+    let mut chip_config = None;
+
+    // Chip must be detected before any firmware operation
+    if args.detect
+        || args.erase
+        || args.write
+        || args.verify
+        || args.validate
+        || args.write_config.is_some()
+    {
+        // Detect
+        output.operation = "detect";
+        say("Detecting");
+        match chprog.detect() {
+            Ok((chip_info, config)) => {
+                chip_config = config;
+                say(&format!("Detected: {}", chip_info));
+                output.chip_id = Some(chip_info.chip_name().to_string());
+                output.bootloader = Some(format!(
+                    "{} {}",
+                    chip_info.bootloader, chip_info.bootloader_version
+                ));
+            }
+            Err(err) => {
+                output.fail(json, format!("Detecting failed: {}", err));
                 return;
             }
         }
+    }
 
-        if args.erase {
-            // Erase
-            if let Err(err) = chprog.erase() {
-                println!("ERROR: Erasing failed: {}", err);
+    if args.validate {
+        // Validate before any flash operation
+        output.operation = "validate";
+        let result = match &args.file {
+            Some(filename) => chprog.validate_hex_file(filename),
+            None => {
+                output.fail(json, "Validation requires --file".to_string());
                 return;
             }
+        };
+        if let Err(err) = result {
+            output.fail(json, format!("Validation failed: {}", err));
+            return;
         }
+        say("Validation OK");
+    }
 
-        if let Some(filename) = args.file {
-            if args.verify && !args.write {
-                // Verify
-                if let Err(err) = chprog.verify(filename) {
-                    println!("ERROR: Verification failed: {}", err);
-                } else {
-                    println!("Verification OK");
-                }
+    if let Some(cfg_word) = args.write_config {
+        // Write config
+        output.operation = "write_config";
+        // Keep option bytes read from chip, replace only config word
+        let config = match chip_config {
+            Some(config) => ChipConfig { cfg_word, ..config },
+            None => {
+                output.fail(
+                    json,
+                    "Config write failed: chip config is not readable with V1 bootloader"
+                        .to_string(),
+                );
                 return;
             }
+        };
+        if let Err(err) = chprog.write_config(config) {
+            output.fail(json, format!("Config write failed: {}", err));
+            return;
+        }
+    }
+
+    if args.erase {
+        // Erase
+        output.operation = "erase";
+        if let Err(err) = chprog.erase() {
+            output.fail(json, format!("Erasing failed: {}", err));
+            return;
+        }
+    }
+
+    if let Some(filename) = args.file {
+        if args.verify && !args.write {
+            // Verify
+            output.operation = "verify";
+            if let Err(err) = chprog.verify(filename) {
+                output.fail(json, format!("Verification failed: {}", err));
+            } else {
+                say("Verification OK");
+            }
+            return;
+        }
 
-            if args.write {
-                // Write
-                if let Err(err) = chprog.flash(filename) {
-                    println!("ERROR: Write failed: {}", err);
-                } else {
-                    println!("Write OK");
-                }
+        if args.write {
+            // Write
+            output.operation = "write";
+            if let Err(err) = chprog.flash(filename) {
+                output.fail(json, format!("Write failed: {}", err));
+            } else {
+                say("Write OK");
             }
         }
-    } else {
-        // Unsuccessful attempt to open port
-        println!("ERROR: Cannot open port: {}", args.port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_baud_rate_accepts_supported_rates() {
+        assert_eq!(parse_baud_rate("9600"), Ok(serial::Baud9600));
+        assert_eq!(parse_baud_rate("19200"), Ok(serial::Baud19200));
+        assert_eq!(parse_baud_rate("38400"), Ok(serial::Baud38400));
+        assert_eq!(parse_baud_rate("57600"), Ok(serial::Baud57600));
+        assert_eq!(parse_baud_rate("115200"), Ok(serial::Baud115200));
+        assert_eq!(parse_baud_rate("230400"), Ok(serial::BaudOther(230400)));
+    }
+
+    #[test]
+    fn parse_baud_rate_rejects_unsupported_rates() {
+        assert_eq!(
+            parse_baud_rate("12345"),
+            Err("unsupported baud rate 12345".to_string())
+        );
+        assert!(parse_baud_rate("fast").is_err());
+        assert!(parse_baud_rate("").is_err());
+    }
+
+    #[test]
+    fn baud_argument_is_checked_before_opening_port() {
+        let args = Args::try_parse_from(["chprog", "--port", "/dev/null", "--baud", "12345"]);
+        assert!(args.is_err());
+    }
+
+    #[test]
+    fn connect_retries_default_to_one_attempt() {
+        let args = Args::try_parse_from(["chprog", "--port", "/dev/null"]).unwrap();
+        assert_eq!(args.connect_retries, 1);
+
+        let args = Args::try_parse_from([
+            "chprog",
+            "--port",
+            "/dev/null",
+            "--no-reset",
+            "--connect-retries",
+            "3",
+        ])
+        .unwrap();
+        assert_eq!(args.connect_retries, 3);
     }
 }
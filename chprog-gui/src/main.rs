@@ -0,0 +1,390 @@
+//! ChProg GUI
+//!
+//! Graphical firmware flasher for WCH CH55x series microcontrollers
+
+use chprog_lib::firmware::FirmwareImage;
+use chprog_lib::protocol::{ChipInfo, ProgressEvent, ProtocolError};
+use chprog_lib::serial::list_ch55x_ports;
+use chprog_lib::ChProg;
+use eframe::egui;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Firmware file extensions offered by file picker
+const FIRMWARE_EXTENSIONS: [&str; 6] = ["bin", "hex", "ihx", "srec", "s19", "mot"];
+
+/// Chip operation requested from the window
+enum Command {
+    Reset,
+    Detect,
+    Erase,
+    Flash(PathBuf),
+    Verify(PathBuf),
+}
+
+/// Worker thread report to the window
+enum Message {
+    /// Fraction of current operation done
+    Progress(f32),
+    /// Status line update
+    Status(String),
+    /// Chip detected
+    Detected(ChipInfo),
+    /// Operation with name finished
+    Done(&'static str, Result<(), ProtocolError>),
+}
+
+/// Channels to the worker thread owning the serial port
+struct Worker {
+    port: String,
+    commands: Sender<Command>,
+    messages: Receiver<Message>,
+    thread: JoinHandle<()>,
+}
+
+impl Worker {
+    /// Spawn worker thread opening [port] and repainting [ctx] on each message
+    fn spawn(port: String, ctx: egui::Context) -> Self {
+        let (commands, command_receiver) = mpsc::channel();
+        let (message_sender, messages) = mpsc::channel();
+
+        let worker_port = port.clone();
+        let thread =
+            thread::spawn(move || worker_run(worker_port, command_receiver, message_sender, ctx));
+
+        Worker {
+            port,
+            commands,
+            messages,
+            thread,
+        }
+    }
+
+    /// Wait for worker thread to finish current command and close the port
+    fn stop(self) {
+        drop(self.commands);
+        self.thread.join().ok();
+    }
+}
+
+// Stop *worker* releasing its port, then look up ports with connected chips
+fn ports_scan(worker: Option<Worker>, ports: Sender<Vec<String>>, ctx: egui::Context) {
+    if let Some(worker) = worker {
+        worker.stop();
+    }
+
+    let found = list_ch55x_ports()
+        .into_iter()
+        .map(|(port, _)| port)
+        .collect();
+    ports.send(found).ok();
+    ctx.request_repaint();
+}
+
+/// Application window state
+#[derive(Default)]
+struct ChProgApp {
+    port: String,
+    ports: Vec<String>,
+    firmware: Option<PathBuf>,
+    worker: Option<Worker>,
+    scan: Option<Receiver<Vec<String>>>,
+    chip_info: Option<ChipInfo>,
+    progress: f32,
+    status: String,
+    busy: bool,
+}
+
+impl ChProgApp {
+    // Pass *command* to the worker, starting it for current port if needed
+    fn command_send(&mut self, ctx: &egui::Context, command: Command) {
+        // Port change takes effect on the next operation
+        if let Some(worker) = &self.worker {
+            if worker.port != self.port {
+                self.worker = None;
+                self.chip_info = None;
+            }
+        }
+
+        let worker = self
+            .worker
+            .get_or_insert_with(|| Worker::spawn(self.port.clone(), ctx.clone()));
+
+        if let Err(mpsc::SendError(command)) = worker.commands.send(command) {
+            // Worker has exited after port failure, try opening port again
+            let worker = Worker::spawn(self.port.clone(), ctx.clone());
+            worker.commands.send(command).ok();
+            self.worker = Some(worker);
+        }
+
+        self.busy = true;
+        self.progress = 0.0;
+    }
+
+    // Start port lookup on a background thread, it needs ports the worker has open
+    fn scan_start(&mut self, ctx: &egui::Context) {
+        let (sender, receiver) = mpsc::channel();
+        let worker = self.worker.take();
+        let ctx = ctx.clone();
+        thread::spawn(move || ports_scan(worker, sender, ctx));
+
+        self.scan = Some(receiver);
+        self.chip_info = None;
+        self.busy = true;
+        self.status = "Scanning ports".to_string();
+    }
+
+    // Apply messages received from the worker and port lookup
+    fn messages_receive(&mut self) {
+        if let Some(Ok(ports)) = self.scan.as_ref().map(|scan| scan.try_recv()) {
+            self.status = format!("Found {} ports", ports.len());
+            if let Some(port) = ports.first() {
+                self.port = port.clone();
+            }
+            self.ports = ports;
+            self.scan = None;
+            self.busy = false;
+        }
+
+        let Some(worker) = &self.worker else {
+            return;
+        };
+
+        while let Ok(message) = worker.messages.try_recv() {
+            match message {
+                Message::Progress(progress) => self.progress = progress,
+                Message::Status(status) => self.status = status,
+                Message::Detected(chip_info) => self.chip_info = Some(chip_info),
+                Message::Done(operation, result) => {
+                    self.busy = false;
+                    self.status = match result {
+                        Ok(()) => format!("{} done", operation),
+                        Err(err) => format!("{} failed: {}", operation, err),
+                    };
+                }
+            }
+        }
+    }
+
+    // Serial port selection and firmware file picker
+    fn settings_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Port:");
+            egui::ComboBox::from_id_salt("port")
+                .selected_text(self.port.as_str())
+                .show_ui(ui, |ui| {
+                    for port in &self.ports {
+                        ui.selectable_value(&mut self.port, port.clone(), port);
+                    }
+                });
+            ui.text_edit_singleline(&mut self.port);
+
+            if ui
+                .add_enabled(!self.busy, egui::Button::new("Scan"))
+                .clicked()
+            {
+                self.scan_start(ui.ctx());
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Firmware:");
+            match &self.firmware {
+                Some(path) => ui.label(path.display().to_string()),
+                None => ui.weak("not selected"),
+            };
+
+            if ui.button("Open…").clicked() {
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("Firmware", &FIRMWARE_EXTENSIONS)
+                    .pick_file()
+                {
+                    self.firmware = Some(path);
+                }
+            }
+        });
+    }
+
+    // Detected chip information
+    fn chip_ui(&self, ui: &mut egui::Ui) {
+        ui.heading("Chip");
+
+        let Some(chip_info) = &self.chip_info else {
+            ui.weak("Not detected");
+            return;
+        };
+
+        egui::Grid::new("chip_info").show(ui, |ui| {
+            ui.label("Model:");
+            ui.label(chip_info.chip_name());
+            ui.end_row();
+
+            ui.label("Chip id:");
+            ui.label(format!("0x{:02X}", chip_info.chip_id));
+            ui.end_row();
+
+            ui.label("Bootloader:");
+            ui.label(chip_info.bootloader.to_string());
+            ui.end_row();
+
+            ui.label("Version:");
+            ui.label(chip_info.bootloader_version.as_str());
+            ui.end_row();
+        });
+    }
+
+    // Operation buttons, progress bar and status line
+    fn operations_ui(&mut self, ui: &mut egui::Ui) {
+        let ctx = ui.ctx().clone();
+        let ready = !self.busy && !self.port.is_empty();
+
+        ui.add_enabled_ui(ready, |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Reset").clicked() {
+                    self.command_send(&ctx, Command::Reset);
+                }
+                if ui.button("Detect").clicked() {
+                    self.command_send(&ctx, Command::Detect);
+                }
+                if ui.button("Erase").clicked() {
+                    self.command_send(&ctx, Command::Erase);
+                }
+
+                let firmware = self.firmware.clone();
+                ui.add_enabled_ui(firmware.is_some(), |ui| {
+                    if ui.button("Flash").clicked() {
+                        if let Some(path) = firmware.clone() {
+                            self.command_send(&ctx, Command::Flash(path));
+                        }
+                    }
+                    if ui.button("Verify").clicked() {
+                        if let Some(path) = firmware {
+                            self.command_send(&ctx, Command::Verify(path));
+                        }
+                    }
+                });
+            });
+        });
+
+        ui.add(egui::ProgressBar::new(self.progress).show_percentage());
+        ui.label(self.status.as_str());
+    }
+}
+
+impl eframe::App for ChProgApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.messages_receive();
+
+        egui::TopBottomPanel::top("settings").show(ctx, |ui| self.settings_ui(ui));
+        egui::SidePanel::left("chip").show(ctx, |ui| self.chip_ui(ui));
+        egui::CentralPanel::default().show(ctx, |ui| self.operations_ui(ui));
+    }
+}
+
+// Open *port* and run chip operations received from *commands*
+fn worker_run(
+    port: String,
+    commands: Receiver<Command>,
+    messages: Sender<Message>,
+    ctx: egui::Context,
+) {
+    let send = |message: Message| {
+        messages.send(message).ok();
+        ctx.request_repaint();
+    };
+
+    let mut chprog = match ChProg::new(&port) {
+        Ok(chprog) => chprog,
+        Err(err) => {
+            send(Message::Done("Connect", Err(err)));
+            return;
+        }
+    };
+
+    // Byte progress of write and verify
+    let progress_messages = messages.clone();
+    let progress_ctx = ctx.clone();
+    chprog.set_progress_callback(Box::new(move |done, total| {
+        progress_messages
+            .send(Message::Progress(done as f32 / total as f32))
+            .ok();
+        progress_ctx.request_repaint();
+    }));
+
+    for command in commands {
+        let (operation, result) = match command {
+            Command::Reset => {
+                chprog.reset();
+                ("Reset", Ok(()))
+            }
+            Command::Detect => ("Detect", detect(&mut chprog, &send)),
+            Command::Erase => (
+                "Erase",
+                detect_once(&mut chprog, &send).and_then(|()| chprog.erase().map(|_| ())),
+            ),
+            Command::Flash(path) => (
+                "Flash",
+                detect_once(&mut chprog, &send).and_then(|()| flash(&mut chprog, &path, &send)),
+            ),
+            Command::Verify(path) => (
+                "Verify",
+                detect_once(&mut chprog, &send).and_then(|()| chprog.verify(path)),
+            ),
+        };
+
+        send(Message::Done(operation, result));
+    }
+}
+
+// Detect connected chip reporting it with *send*
+fn detect(chprog: &mut ChProg, send: &dyn Fn(Message)) -> Result<(), ProtocolError> {
+    let (chip_info, _) = chprog.detect()?;
+    send(Message::Detected(chip_info));
+
+    Ok(())
+}
+
+// Detect chip unless it is already detected in bootloader mode
+fn detect_once(chprog: &mut ChProg, send: &dyn Fn(Message)) -> Result<(), ProtocolError> {
+    if chprog.chip_info().is_in_bootloader() {
+        return Ok(());
+    }
+
+    detect(chprog, send)
+}
+
+// Write firmware at *path* reporting write stages with *send*
+fn flash(chprog: &mut ChProg, path: &Path, send: &dyn Fn(Message)) -> Result<(), ProtocolError> {
+    let image = FirmwareImage::load_from_file(path)?;
+    let data: Arc<[u8]> = image.as_bytes().into();
+
+    for event in chprog.flash_progress_iter(data) {
+        match event? {
+            ProgressEvent::Erased => send(Message::Status("Writing".to_string())),
+            ProgressEvent::TimeRemaining { seconds } => {
+                send(Message::Status(format!("Writing, {:.0} s left", seconds)))
+            }
+            ProgressEvent::VerifyPacket { .. } => send(Message::Status("Verifying".to_string())),
+            ProgressEvent::WritePacket { .. } => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> eframe::Result {
+    env_logger::init();
+
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default().with_inner_size([560.0, 240.0]),
+        ..Default::default()
+    };
+
+    eframe::run_native(
+        "ChProg",
+        options,
+        Box::new(|_cc| Ok(Box::<ChProgApp>::default())),
+    )
+}
@@ -0,0 +1,36 @@
+//! Request exchange benchmark
+//!
+//! Reply is read in chunks, so a request costs a few port reads regardless of reply length
+
+use chprog_lib::protocol::{packet_checksum, Protocol, REPLY_PREAMBLE};
+use chprog_lib::testing::MockSerialPort;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+// Reply frame carrying *payload*
+fn reply(payload: &[u8]) -> Vec<u8> {
+    let mut frame = REPLY_PREAMBLE.to_vec();
+    frame.extend_from_slice(payload);
+    frame.push(packet_checksum(payload));
+    frame
+}
+
+fn request_send(c: &mut Criterion) {
+    let port = MockSerialPort::new();
+    let mut protocol = Protocol::new(Box::new(port.clone()), "bench");
+    let detect_reply = reply(&[0xA1, 0x00, 0x02, 0x00, 0x52, 0x11]);
+
+    // Bootloader detection sends one request and reads its 6 bytes reply
+    c.bench_function("detect request", |b| {
+        b.iter(|| {
+            let mut state = port.state();
+            state.sent_packets.clear();
+            state.queued_responses.push(detect_reply.clone());
+            drop(state);
+
+            protocol.try_detect_bootloader_version().unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, request_send);
+criterion_main!(benches);
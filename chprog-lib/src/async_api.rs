@@ -0,0 +1,632 @@
+//! ChProg async API file
+//!
+//! Firmware operations for async applications, requests are exchanged
+//! through tokio I/O and never block the executor, protocol logic
+//! is shared with [Protocol]
+
+use super::firmware::FirmwareImage;
+use super::protocol::{
+    ChipConfig, ChipInfo, EraseBlockResult, EraseRequest, FlashCursor, KeyRequest, Mode,
+    ProgramOptions, Protocol, ProtocolError, ResetPolarity, SerialErrorKind, V1Config, CHUNK_SIZE,
+};
+use super::sequence::Bootloader;
+use super::ChProg;
+use log::{debug, info, warn};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+
+/// Async chip firmware operations over serial port *P*
+pub struct ChProgAsync<P = SerialStream> {
+    port: P,
+    port_name: String,
+    chip_info: ChipInfo,
+    bootkey: [u8; 8],
+    timeout: Duration,
+    max_retries: u8,
+    preferred_bootloader: Option<Bootloader>,
+    options: ProgramOptions,
+    operation_timeout: Duration,
+    deadline: Option<Instant>,
+    reset_polarity: ResetPolarity,
+    v1_config: V1Config,
+}
+
+impl ChProgAsync<SerialStream> {
+    /// Opens specified [serial_port] at 57600 baud 8N1,
+    /// must be called within tokio runtime
+    pub fn new(serial_port: impl AsRef<str>) -> Result<Self, ProtocolError> {
+        let port_name = serial_port.as_ref();
+        match tokio_serial::new(port_name, 57600).open_native_async() {
+            Ok(port) => Ok(ChProgAsync::with_port(port, port_name)),
+            Err(_) => Err(ProtocolError::SerialError {
+                port: port_name.to_string(),
+                kind: SerialErrorKind::Open,
+            }),
+        }
+    }
+
+    /// Execute chip reset sequence, chip must be detected again
+    pub async fn reset(&mut self) {
+        let polarity = self.reset_polarity;
+        self.chip_info = ChipInfo::undetected();
+        self.bootkey = [0; 8];
+
+        time::sleep(Duration::from_millis(10)).await;
+
+        // Set RST(DTR line) & BOOT(RTS line)
+        self.port
+            .write_data_terminal_ready(polarity.dtr_active_high)
+            .ok();
+        self.port
+            .write_request_to_send(polarity.rts_active_high)
+            .ok();
+        time::sleep(Duration::from_millis(150)).await;
+
+        // Unset RST(DTR line)
+        self.port
+            .write_data_terminal_ready(!polarity.dtr_active_high)
+            .ok();
+
+        // Unset BOOT(RTS line) to start bootloader
+        time::sleep(Duration::from_millis(100)).await;
+        self.port
+            .write_request_to_send(!polarity.rts_active_high)
+            .ok();
+
+        // Settle bootloader
+        time::sleep(Duration::from_millis(250)).await;
+    }
+
+    /// Set reset lines *polarity* used by [ChProgAsync::reset]
+    pub fn set_reset_polarity(&mut self, polarity: ResetPolarity) {
+        self.reset_polarity = polarity;
+    }
+}
+
+impl<P: AsyncRead + AsyncWrite + Unpin> ChProgAsync<P> {
+    /// Wrap already opened *port*, *port_name* is used for error reporting
+    pub fn with_port(port: P, port_name: impl Into<String>) -> Self {
+        ChProgAsync {
+            port,
+            port_name: port_name.into(),
+            chip_info: ChipInfo::undetected(),
+            bootkey: [0; 8],
+            timeout: Protocol::DEFAULT_TIMEOUT,
+            max_retries: Protocol::DEFAULT_MAX_RETRIES,
+            preferred_bootloader: None,
+            options: ProgramOptions::default(),
+            operation_timeout: ChProg::DEFAULT_OPERATION_TIMEOUT,
+            deadline: None,
+            reset_polarity: ResetPolarity::CH55X_STANDARD,
+            v1_config: V1Config::default(),
+        }
+    }
+
+    /// Set serial read *timeout*
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Set number of request resend attempts after transport error,
+    /// 0 fails on the first error
+    pub fn set_max_retries(&mut self, max_retries: u8) {
+        self.max_retries = max_retries;
+    }
+
+    /// Restrict bootloader detection to *bootloader* version,
+    /// [Bootloader::Unknown] allows any version
+    pub fn set_preferred_bootloader(&mut self, bootloader: Bootloader) {
+        self.preferred_bootloader = match bootloader {
+            Bootloader::Unknown => None,
+            _ => Some(bootloader),
+        };
+    }
+
+    /// Set firmware programming *options*
+    pub fn set_program_options(&mut self, options: ProgramOptions) {
+        self.options = options;
+    }
+
+    /// Set time given to complete erase, flash or verify
+    pub fn set_operation_timeout(&mut self, timeout: Duration) {
+        self.operation_timeout = timeout;
+    }
+
+    /// Set V1 bootloader reply codes for bootloader versions that differ from defaults
+    pub fn set_v1_config(&mut self, config: V1Config) {
+        self.v1_config = config;
+    }
+
+    /// Detected chip information
+    pub fn chip_info(&self) -> &ChipInfo {
+        &self.chip_info
+    }
+
+    /// Detect chip and return its information and config, [None] for V1 bootloader
+    pub async fn detect(&mut self) -> Result<(ChipInfo, Option<ChipConfig>), ProtocolError> {
+        self.chip_info = ChipInfo::undetected();
+        self.bootloader_detect().await?;
+        let config = self.chip_detect().await?;
+
+        Ok((self.chip_info.clone(), config))
+    }
+
+    /// Erase chip flash memory, returning result of each erased block
+    pub async fn erase(&mut self) -> Result<Vec<EraseBlockResult>, ProtocolError> {
+        self.bootloader_check()?;
+
+        self.deadline = Some(Instant::now() + self.operation_timeout);
+        let result = self.erase_blocks(0, None).await;
+        self.deadline = None;
+
+        result
+    }
+
+    /// Erase single flash block at *block_index*
+    pub async fn erase_block(&mut self, block_index: u8) -> Result<(), ProtocolError> {
+        self.erase_range(block_index, 1).await
+    }
+
+    /// Erase *count* flash blocks starting from block *start*,
+    /// V2 bootloader erases from the start of flash only
+    pub async fn erase_range(&mut self, start: u8, count: u8) -> Result<(), ProtocolError> {
+        self.bootloader_check()?;
+
+        self.deadline = Some(Instant::now() + self.operation_timeout);
+        let result = self.erase_blocks(start, Some(count)).await;
+        self.deadline = None;
+
+        result.map(|_| ())
+    }
+
+    /// Write flash firmware with specified [filename], binary, Intel HEX or S-record
+    pub async fn flash(&mut self, filename: impl AsRef<Path>) -> Result<(), ProtocolError> {
+        let image = Self::firmware_load(filename).await?;
+        self.flash_bytes(image.as_bytes()).await
+    }
+
+    /// Write flash firmware from already loaded [data], verify it and exit bootloader
+    pub async fn flash_bytes(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        self.bootloader_check()?;
+
+        self.deadline = Some(Instant::now() + self.operation_timeout);
+        let result = self.flash_verified(data).await;
+        self.deadline = None;
+
+        result
+    }
+
+    /// Verify flash firmware with specified [filename], binary, Intel HEX or S-record
+    pub async fn verify(&mut self, filename: impl AsRef<Path>) -> Result<(), ProtocolError> {
+        let image = Self::firmware_load(filename).await?;
+        self.verify_bytes(image.as_bytes()).await
+    }
+
+    /// Verify flash firmware with already loaded [data]
+    pub async fn verify_bytes(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        self.bootloader_check()?;
+
+        self.deadline = Some(Instant::now() + self.operation_timeout);
+        let result = self.verify_buffer(data).await;
+        self.deadline = None;
+
+        result
+    }
+
+    // Load firmware image from file specified in *filename*
+    async fn firmware_load(filename: impl AsRef<Path>) -> Result<FirmwareImage, ProtocolError> {
+        let file_buffer = match tokio::fs::read(filename).await {
+            Ok(file_buffer) => file_buffer,
+            Err(_) => return Err(ProtocolError::FileAccessError),
+        };
+
+        FirmwareImage::try_from(Protocol::firmware_parse(file_buffer)?)
+    }
+
+    // Detect bootloader unless it is already detected
+    async fn bootloader_detect(&mut self) -> Result<Bootloader, ProtocolError> {
+        self.flush_rx().await;
+
+        // Check if bootloader is already detected
+        if self.chip_info.bootloader != Bootloader::Unknown {
+            return Ok(self.chip_info.bootloader);
+        }
+
+        let result = async {
+            let detect_sequence = Protocol::detect_sequence(self.preferred_bootloader)?;
+            let reply = self.request_send(detect_sequence).await?;
+            Protocol::detect_reply_bootloader(&reply, self.preferred_bootloader)
+        }
+        .await;
+
+        let bootloader = result.map_err(Protocol::bootloader_detect_error)?;
+        self.chip_info.bootloader = bootloader;
+
+        Ok(bootloader)
+    }
+
+    // Identify chip and read its config, [None] for V1 bootloader
+    async fn chip_detect(&mut self) -> Result<Option<ChipConfig>, ProtocolError> {
+        self.flush_rx().await;
+        let sequence = Protocol::bootloader_sequence(self.chip_info.bootloader)?;
+
+        // Identify chip
+        let reply = self.request_send(sequence.chip_detect).await?;
+        Protocol::chip_identify(&mut self.chip_info, &reply)?;
+
+        // Read config
+        let reply = self.request_send(sequence.config_read).await?;
+        let config = Protocol::config_apply(&mut self.chip_info, &reply)?;
+
+        if config.is_some() {
+            // Key exchange sets bootkey for V2 packet data, zero key is used otherwise
+            if Protocol::boot_key_required(self.chip_info.chip_id) {
+                let key_request = KeyRequest::new(self.chip_info.chip_id, &reply);
+                self.bootkey = key_request.bootkey;
+
+                let key_reply = self.request_send(&key_request.request).await?;
+                key_request.reply_check(&key_reply)?;
+            } else {
+                self.bootkey = [0; 8];
+            }
+        }
+
+        // Chip parameters are required for further operations
+        Protocol::chip_defined_check(self.chip_info.chip_id)?;
+
+        Ok(config)
+    }
+
+    // Erase, write and verify firmware *data*, then exit bootloader
+    async fn flash_verified(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        // Check that firmware fits before erasing
+        Protocol::firmware_size_check(self.chip_info.chip_id, data.len())?;
+        self.erase_blocks(0, None).await?;
+
+        self.flash_buffer(data, Mode::Write).await?;
+        self.flash_buffer(data, Mode::Verify).await?;
+
+        // Exit bootloader
+        let sequence = Protocol::bootloader_sequence(self.chip_info.bootloader)?;
+        self.request_send(sequence.bootloader_exit).await?;
+
+        Ok(())
+    }
+
+    // Detect chip again and verify firmware *data*
+    async fn verify_buffer(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        self.bootloader_detect().await?;
+        self.chip_detect().await?;
+
+        self.flash_buffer(data, Mode::Verify).await
+    }
+
+    // Erase *count* blocks from *start*, all chip blocks if *count* is not set
+    async fn erase_blocks(
+        &mut self,
+        start: u8,
+        count: Option<u8>,
+    ) -> Result<Vec<EraseBlockResult>, ProtocolError> {
+        self.flush_rx().await;
+        let erase_requests = Protocol::erase_requests(&self.chip_info, start, count)?;
+
+        // Erase takes longer than regular request
+        let timeout = self.timeout;
+        self.timeout = Protocol::erase_timeout(self.chip_info.chip_id);
+        let result = self.erase_flash(&erase_requests).await;
+        self.timeout = timeout;
+
+        result
+    }
+
+    // Send *erase_requests* with current timeout
+    async fn erase_flash(
+        &mut self,
+        erase_requests: &[EraseRequest],
+    ) -> Result<Vec<EraseBlockResult>, ProtocolError> {
+        let mut erase_results = Vec::new();
+
+        for erase_request in erase_requests {
+            let Some(block_index) = erase_request.block_index else {
+                // Request preceding V1 block erase, its reply is not checked
+                if self.request_send(&erase_request.request).await.is_err() {
+                    return Err(self.chip_unknown());
+                }
+                continue;
+            };
+
+            Protocol::deadline_check(self.deadline)?;
+            debug!("Erasing block: {}", block_index);
+
+            let reply = self.request_send(&erase_request.request).await?;
+            let result =
+                Protocol::erase_reply_result(self.chip_info.bootloader, block_index, &reply)?;
+            erase_results.push(result);
+            if !result.success {
+                warn!("Erase failed");
+                return Err(self.chip_unknown());
+            }
+        }
+
+        info!("Flash erased");
+        Ok(erase_results)
+    }
+
+    // Send firmware *data* packets in *mode* and check their reply status
+    async fn flash_buffer(&mut self, data: &[u8], mode: Mode) -> Result<(), ProtocolError> {
+        let mut cursor =
+            FlashCursor::new(data, Some(data.len()), CHUNK_SIZE, mode, &self.chip_info)?;
+
+        while let Some(packet) = cursor.packet_next(&self.bootkey, self.options.skip_blank_pages)? {
+            Protocol::deadline_check(self.deadline)?;
+            self.packet_send(&packet.data, &cursor.mode, packet.address)
+                .await?;
+        }
+
+        debug!("Writing success");
+        Ok(())
+    }
+
+    // Send *packet* for *address* and check reply status,
+    // resend it up to `packet_retries` times on failure other than verify mismatch
+    async fn packet_send(
+        &mut self,
+        packet: &[u8],
+        mode: &Mode,
+        address: usize,
+    ) -> Result<(), ProtocolError> {
+        let mut retries = 0;
+        loop {
+            Protocol::deadline_check(self.deadline)?;
+
+            match self.packet_send_once(packet, mode, address).await {
+                Err(err)
+                    if Protocol::packet_retryable(&err)
+                        && retries < self.options.packet_retries =>
+                {
+                    retries += 1;
+
+                    // Drop rest of failed reply before resending
+                    self.flush_rx().await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Send *packet* for *address* once and check reply status
+    async fn packet_send_once(
+        &mut self,
+        packet: &[u8],
+        mode: &Mode,
+        address: usize,
+    ) -> Result<(), ProtocolError> {
+        let reply = self.request_send(packet).await?;
+
+        Protocol::packet_status_check(
+            self.chip_info.bootloader,
+            mode,
+            &reply,
+            address,
+            &self.v1_config,
+        )
+    }
+
+    // Send *sequence* to MCU and return reply,
+    // transport errors are retried up to `max_retries` times
+    async fn request_send(&mut self, sequence: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let mut retries = 0;
+        loop {
+            match self.request_send_once(sequence).await {
+                Err(err) if Protocol::request_retryable(&err) && retries < self.max_retries => {
+                    retries += 1;
+
+                    // Drop rest of broken reply before resending
+                    self.flush_rx().await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Send *sequence* to MCU once and return its reply payload
+    async fn request_send_once(&mut self, sequence: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        // Write serial
+        if self
+            .port
+            .write_all(&Protocol::request_frame(sequence))
+            .await
+            .is_err()
+        {
+            return Err(self.serial_error(SerialErrorKind::WriteFailed));
+        }
+
+        // Give chip time to prepare reply
+        let response_delay_ms =
+            Protocol::response_delay_ms(self.chip_info.bootloader, self.preferred_bootloader);
+        if response_delay_ms > 0 {
+            time::sleep(Duration::from_millis(response_delay_ms)).await;
+        }
+
+        // Read available reply bytes until timeout
+        let mut reply = [0; Protocol::PACKET_MAXLEN];
+        let mut reply_len = 0;
+        while reply_len < reply.len() {
+            match time::timeout(self.timeout, self.port.read(&mut reply[reply_len..])).await {
+                Ok(Ok(read_len)) if read_len > 0 => reply_len += read_len,
+                _ => break,
+            }
+        }
+
+        // Process packet if remote device replied
+        if reply_len == 0 {
+            debug!("Serial read timeout");
+            return Err(self.serial_error(SerialErrorKind::Timeout));
+        }
+
+        Protocol::reply_payload(&reply[..reply_len])
+    }
+
+    // Drop stale bytes left in serial read buffer by previous operation
+    async fn flush_rx(&mut self) {
+        let mut buffer = [0; Protocol::PACKET_MAXLEN];
+        while let Ok(Ok(read_len)) =
+            time::timeout(Protocol::FLUSH_TIMEOUT, self.port.read(&mut buffer)).await
+        {
+            if read_len == 0 {
+                break;
+            }
+        }
+    }
+
+    // Check that chip was detected in bootloader mode
+    fn bootloader_check(&self) -> Result<(), ProtocolError> {
+        if !self.chip_info.is_in_bootloader() {
+            return Err(ProtocolError::ChipNotInBootloader);
+        }
+
+        Ok(())
+    }
+
+    // Unknown chip error for current chip id
+    fn chip_unknown(&self) -> ProtocolError {
+        ProtocolError::ChipUnknown {
+            chip_id: self.chip_info.chip_id,
+        }
+    }
+
+    // Serial error of *kind* on the current port
+    fn serial_error(&self, kind: SerialErrorKind) -> ProtocolError {
+        ProtocolError::SerialError {
+            port: self.port_name.clone(),
+            kind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{packet_checksum, PREAMBLE_LEN, REPLY_PREAMBLE};
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+
+    // Async chip connected to CH552 in V1 bootloader accepting every request,
+    // the task returns command bytes of received requests once the port is closed
+    fn v1_chprog() -> (ChProgAsync<DuplexStream>, JoinHandle<Vec<u8>>) {
+        let (port, mut chip) = tokio::io::duplex(Protocol::PACKET_MAXLEN * 4);
+
+        let chip_task = tokio::spawn(async move {
+            let mut commands = Vec::new();
+            let mut frame = [0; Protocol::PACKET_MAXLEN];
+            while let Ok(frame_len) = chip.read(&mut frame).await {
+                if frame_len <= PREAMBLE_LEN {
+                    break;
+                }
+
+                let command = frame[PREAMBLE_LEN];
+                commands.push(command);
+                let payload: &[u8] = match command {
+                    0xA1 | 0xA2 => &[0x52, 0x11],
+                    0xBB => &[0x21, 0x03],
+                    _ => &[0x00],
+                };
+
+                let mut reply = REPLY_PREAMBLE.to_vec();
+                reply.extend_from_slice(payload);
+                reply.push(packet_checksum(payload));
+                if chip.write_all(&reply).await.is_err() {
+                    break;
+                }
+            }
+
+            commands
+        });
+
+        (ChProgAsync::with_port(port, "mock"), chip_task)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn detect_v1_chip() {
+        let (mut chprog, chip_task) = v1_chprog();
+
+        let (chip_info, config) = chprog.detect().await.unwrap();
+        assert_eq!(chip_info.bootloader, Bootloader::V1);
+        assert_eq!(chip_info.chip_id, 0x52);
+        assert!(config.is_none());
+
+        drop(chprog);
+        assert_eq!(chip_task.await.unwrap(), [0xA1, 0xA2, 0xBB]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn detect_sends_preferred_bootloader_request_only() {
+        let (mut chprog, chip_task) = v1_chprog();
+        chprog.set_preferred_bootloader(Bootloader::V1);
+
+        let (chip_info, _) = chprog.detect().await.unwrap();
+        assert_eq!(chip_info.bootloader, Bootloader::V1);
+
+        drop(chprog);
+        assert_eq!(chip_task.await.unwrap(), [0xA2, 0xA2, 0xBB]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn erase_range_erases_requested_blocks() {
+        let (mut chprog, chip_task) = v1_chprog();
+        chprog.detect().await.unwrap();
+        chprog.erase_range(2, 3).await.unwrap();
+
+        drop(chprog);
+        assert_eq!(
+            chip_task.await.unwrap(),
+            [0xA1, 0xA2, 0xBB, 0xA6, 0xA9, 0xA9, 0xA9]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flash_bytes_sends_erase_write_verify_exit() {
+        let (mut chprog, chip_task) = v1_chprog();
+        chprog.detect().await.unwrap();
+        chprog.flash_bytes(&[0x02; 64]).await.unwrap();
+
+        drop(chprog);
+        let commands = chip_task.await.unwrap();
+
+        // Erase request and 14 blocks of CH552, 2 packets written and verified
+        let mut expected = vec![0xA1, 0xA2, 0xBB, 0xA6];
+        expected.extend([0xA9; 14]);
+        expected.extend([0xA8, 0xA8, 0xA7, 0xA7, 0xA5]);
+        assert_eq!(commands, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flash_bytes_requires_detected_chip() {
+        let (mut chprog, chip_task) = v1_chprog();
+
+        assert_eq!(
+            chprog.flash_bytes(&[0x02; 64]).await,
+            Err(ProtocolError::ChipNotInBootloader)
+        );
+
+        drop(chprog);
+        assert!(chip_task.await.unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn detect_reports_unknown_bootloader_on_silent_port() {
+        let (port, _chip) = tokio::io::duplex(Protocol::PACKET_MAXLEN);
+        let mut chprog = ChProgAsync::with_port(port, "mock");
+        chprog.set_timeout(Duration::from_millis(20));
+        chprog.set_max_retries(0);
+
+        // Read timeout is reported like by sync bootloader detection
+        assert_eq!(
+            chprog.detect().await.map(|_| ()),
+            Err(ProtocolError::BootloaderUnknown)
+        );
+    }
+}
@@ -2,67 +2,299 @@
 //!
 //! Each microcontroller type have variables concerning memory capacity and boot parameters
 
+use super::sequence::Bootloader;
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::LazyLock;
+
+/// Microcontroller family
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChipFamily {
+    CH32V103,
+    CH549,
+    CH551,
+    CH552,
+    CH553,
+    CH554,
+    CH558,
+    CH559,
+    CH569,
+    /// Unsupported or not identified chip
+    Unknown,
+}
+
+impl From<u8> for ChipFamily {
+    fn from(chip_id: u8) -> Self {
+        match chip_id {
+            0x3F => ChipFamily::CH32V103,
+            0x49 => ChipFamily::CH549,
+            0x51 => ChipFamily::CH551,
+            0x52 => ChipFamily::CH552,
+            0x53 => ChipFamily::CH553,
+            0x54 => ChipFamily::CH554,
+            0x58 => ChipFamily::CH558,
+            0x59 => ChipFamily::CH559,
+            0x69 => ChipFamily::CH569,
+            _ => ChipFamily::Unknown,
+        }
+    }
+}
+
+impl ChipFamily {
+    /// Chip model name like "CH552"
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChipFamily::CH32V103 => "CH32V103",
+            ChipFamily::CH549 => "CH549",
+            ChipFamily::CH551 => "CH551",
+            ChipFamily::CH552 => "CH552",
+            ChipFamily::CH553 => "CH553",
+            ChipFamily::CH554 => "CH554",
+            ChipFamily::CH558 => "CH558",
+            ChipFamily::CH559 => "CH559",
+            ChipFamily::CH569 => "CH569",
+            ChipFamily::Unknown => "Unknown",
+        }
+    }
+}
+
+impl fmt::Display for ChipFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
 
 #[derive(Clone, Copy)]
 pub struct Definition {
-    pub flash_blocks: u8,
+    /// Chip id reported by bootloader
+    pub chip_id: u8,
+    /// Device type reported along with chip id in V2 chip detect reply
+    pub device_type: u8,
+    /// Bootloader versions the chip is flashed with
+    pub bootloaders: &'static [Bootloader],
+    pub flash_blocks: u16,
     pub erase_blocks: u8,
     pub boot_address: u32,
+    /// On-chip xRAM in whole KiB, chips with 512 bytes of xRAM have 0
+    pub xram_kib: u16,
+    pub iram_bytes: u16,
+    pub erase_timeout_ms: u64,
+    /// Separate DATA flash region size in bytes, 0 if chip has none
+    pub data_flash_size: u32,
+    /// Maximum firmware bytes in a single V1 packet
+    pub max_packet_payload_v1: u8,
+    /// Maximum firmware bytes in a single V2 packet, multiple of 8
+    pub max_packet_payload_v2: u8,
+    /// V2 bootloader requires key exchange before writing
+    pub boot_key_required: bool,
+    /// Smaller firmware is considered corrupt
+    pub minimum_firmware_bytes: usize,
+}
+
+impl Definition {
+    /// Family of defined chip
+    pub fn chip_family(&self) -> ChipFamily {
+        ChipFamily::from(self.chip_id)
+    }
 }
 
-lazy_static! {
-    pub static ref DEFINITIONS: HashMap<u8, Definition> = [
+pub static DEFINITIONS: LazyLock<HashMap<u8, Definition>> = LazyLock::new(|| {
+    [
+        (
+            0x3F, // CH32V103, RISC-V chip with the same ISP protocol
+            Definition {
+                chip_id: 0x3F,
+                device_type: 0x15,
+                bootloaders: &[Bootloader::V1, Bootloader::V2],
+                flash_blocks: 64,
+                erase_blocks: 64,
+                boot_address: 0x10000, // Bootloader is in separate system flash
+                xram_kib: 20,
+                iram_bytes: 0,
+                erase_timeout_ms: 5000,
+                data_flash_size: 0,
+                max_packet_payload_v1: 60,
+                max_packet_payload_v2: 56,
+                boot_key_required: true,
+                minimum_firmware_bytes: 32,
+            },
+        ),
+        (
+            0x49, // CH549
+            Definition {
+                chip_id: 0x49,
+                device_type: 0x11,
+                bootloaders: &[Bootloader::V1, Bootloader::V2],
+                flash_blocks: 64,
+                erase_blocks: 60,
+                boot_address: 0xF400,
+                xram_kib: 2,
+                iram_bytes: 256,
+                erase_timeout_ms: 5000,
+                data_flash_size: 1024,
+                max_packet_payload_v1: 60,
+                max_packet_payload_v2: 56,
+                boot_key_required: true,
+                minimum_firmware_bytes: 32,
+            },
+        ),
         (
             0x51, // CH551
             Definition {
+                chip_id: 0x51,
+                device_type: 0x11,
+                bootloaders: &[Bootloader::V1, Bootloader::V2],
                 flash_blocks: 10,
                 erase_blocks: 10,
                 boot_address: 0x3800,
-            }
+                xram_kib: 0, // 512 bytes of xRAM
+                iram_bytes: 256,
+                erase_timeout_ms: 1000,
+                data_flash_size: 128,
+                max_packet_payload_v1: 60,
+                max_packet_payload_v2: 56,
+                boot_key_required: true,
+                minimum_firmware_bytes: 32,
+            },
         ),
         (
             0x52, // CH552
             Definition {
+                chip_id: 0x52,
+                device_type: 0x11,
+                bootloaders: &[Bootloader::V1, Bootloader::V2],
                 flash_blocks: 16,
                 erase_blocks: 14,
                 boot_address: 0x3800,
-            }
+                xram_kib: 1,
+                iram_bytes: 256,
+                erase_timeout_ms: 1500,
+                data_flash_size: 128,
+                max_packet_payload_v1: 60,
+                max_packet_payload_v2: 56,
+                boot_key_required: true,
+                minimum_firmware_bytes: 32,
+            },
         ),
         (
             0x53, // CH553
             Definition {
+                chip_id: 0x53,
+                device_type: 0x11,
+                bootloaders: &[Bootloader::V1, Bootloader::V2],
                 flash_blocks: 10,
                 erase_blocks: 10,
                 boot_address: 0x3800,
-            }
+                xram_kib: 0, // 512 bytes of xRAM
+                iram_bytes: 256,
+                erase_timeout_ms: 1000,
+                data_flash_size: 128,
+                max_packet_payload_v1: 60,
+                max_packet_payload_v2: 56,
+                boot_key_required: true,
+                minimum_firmware_bytes: 32,
+            },
         ),
         (
             0x54, // CH554
             Definition {
+                chip_id: 0x54,
+                device_type: 0x11,
+                bootloaders: &[Bootloader::V1, Bootloader::V2],
                 flash_blocks: 16,
                 erase_blocks: 14,
                 boot_address: 0x3800,
-            }
+                xram_kib: 1,
+                iram_bytes: 256,
+                erase_timeout_ms: 1500,
+                data_flash_size: 128,
+                max_packet_payload_v1: 60,
+                max_packet_payload_v2: 56,
+                boot_key_required: true,
+                minimum_firmware_bytes: 32,
+            },
         ),
         (
             0x58, // CH558
             Definition {
+                chip_id: 0x58,
+                device_type: 0x11,
+                bootloaders: &[Bootloader::V1, Bootloader::V2],
                 flash_blocks: 40,
                 erase_blocks: 32,
                 boot_address: 0xF400,
-            }
+                xram_kib: 2,
+                iram_bytes: 256,
+                erase_timeout_ms: 3000,
+                data_flash_size: 1024,
+                max_packet_payload_v1: 60,
+                max_packet_payload_v2: 56,
+                boot_key_required: true,
+                minimum_firmware_bytes: 32,
+            },
         ),
         (
             0x59, // CH559
             Definition {
+                chip_id: 0x59,
+                device_type: 0x11,
+                bootloaders: &[Bootloader::V1, Bootloader::V2],
                 flash_blocks: 64,
                 erase_blocks: 60,
                 boot_address: 0xF400,
-            }
+                xram_kib: 6,
+                iram_bytes: 256,
+                erase_timeout_ms: 5000,
+                data_flash_size: 1024,
+                max_packet_payload_v1: 60,
+                max_packet_payload_v2: 56,
+                boot_key_required: true,
+                minimum_firmware_bytes: 32,
+            },
+        ),
+        (
+            0x69, // CH569, uses V3 bootloader
+            Definition {
+                chip_id: 0x69,
+                device_type: 0x10,
+                bootloaders: &[Bootloader::V3],
+                flash_blocks: 448,
+                erase_blocks: 0, // Erased by V3 bootloader only
+                boot_address: 0x70000,
+                xram_kib: 96,
+                iram_bytes: 0,
+                erase_timeout_ms: 5000,
+                data_flash_size: 32768,
+                max_packet_payload_v1: 60,
+                max_packet_payload_v2: 56,
+                boot_key_required: true,
+                minimum_firmware_bytes: 32,
+            },
         ),
     ]
     .iter()
     .copied()
-    .collect();
+    .collect()
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn definitions_populated_on_first_access() {
+        assert_eq!(DEFINITIONS.len(), 9);
+
+        let ch552 = DEFINITIONS[&0x52];
+        assert_eq!(ch552.flash_blocks, 16);
+        assert_eq!(ch552.boot_address, 0x3800);
+    }
+
+    #[test]
+    fn definitions_keyed_by_chip_id() {
+        for (chip_id, definition) in DEFINITIONS.iter() {
+            assert_eq!(*chip_id, definition.chip_id);
+            assert_ne!(definition.chip_family(), ChipFamily::Unknown);
+        }
+    }
 }
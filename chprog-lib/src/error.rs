@@ -0,0 +1,115 @@
+//! ChProg error file
+//!
+//! Adding context to protocol errors without external dependencies
+
+use super::protocol::ProtocolError;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+
+/// Error annotated with a context describing the failed operation
+pub struct AnnotatedError<E> {
+    context: Box<dyn fmt::Display + Send + Sync>,
+    error: E,
+}
+
+impl<E> AnnotatedError<E> {
+    /// Wrap *error* with *context*
+    pub fn new<C: fmt::Display + Send + Sync + 'static>(error: E, context: C) -> Self {
+        AnnotatedError {
+            context: Box::new(context),
+            error,
+        }
+    }
+
+    /// Original error without context
+    pub fn into_inner(self) -> E {
+        self.error
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for AnnotatedError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.context, self.error)
+    }
+}
+
+impl<E: fmt::Debug> fmt::Debug for AnnotatedError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AnnotatedError")
+            .field("context", &self.context.to_string())
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl<E: Error + 'static> Error for AnnotatedError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl<E> Deref for AnnotatedError<E> {
+    type Target = E;
+
+    fn deref(&self) -> &Self::Target {
+        &self.error
+    }
+}
+
+/// Extension for annotating protocol results with a context
+pub trait ProtocolResultExt<T> {
+    /// Wrap error with *ctx* describing the failed operation
+    fn with_context<C: fmt::Display + Send + Sync + 'static>(
+        self,
+        ctx: C,
+    ) -> Result<T, AnnotatedError<ProtocolError>>;
+}
+
+impl<T> ProtocolResultExt<T> for Result<T, ProtocolError> {
+    fn with_context<C: fmt::Display + Send + Sync + 'static>(
+        self,
+        ctx: C,
+    ) -> Result<T, AnnotatedError<ProtocolError>> {
+        self.map_err(|error| AnnotatedError::new(error, ctx))
+    }
+}
+
+/// I/O error compared by its kind, keeps [ProtocolError] comparable
+#[derive(Debug)]
+pub struct IoError(pub io::Error);
+
+impl IoError {
+    /// Kind of wrapped I/O error
+    pub fn kind(&self) -> io::ErrorKind {
+        self.0.kind()
+    }
+}
+
+// I/O errors are not cloneable, copy keeps kind and message
+impl Clone for IoError {
+    fn clone(&self) -> Self {
+        IoError(io::Error::new(self.kind(), self.0.to_string()))
+    }
+}
+
+impl PartialEq for IoError {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind() == other.kind()
+    }
+}
+
+impl Eq for IoError {}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for IoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.0.source()
+    }
+}
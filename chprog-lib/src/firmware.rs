@@ -0,0 +1,51 @@
+//! ChProg firmware file
+//!
+//! Firmware image loaded into memory before flashing
+
+use super::protocol::{Protocol, ProtocolError};
+use std::path::Path;
+
+/// Firmware binary image
+pub struct FirmwareImage {
+    data: Vec<u8>,
+}
+
+impl FirmwareImage {
+    /// Load firmware image from binary, Intel HEX or S-record file at *path*
+    pub fn load_from_file(path: &Path) -> Result<Self, ProtocolError> {
+        Self::try_from(Protocol::load_file(path)?)
+    }
+
+    /// Firmware binary data
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl TryFrom<&Path> for FirmwareImage {
+    type Error = ProtocolError;
+
+    fn try_from(path: &Path) -> Result<Self, Self::Error> {
+        Self::load_from_file(path)
+    }
+}
+
+impl TryFrom<Vec<u8>> for FirmwareImage {
+    type Error = ProtocolError;
+
+    fn try_from(data: Vec<u8>) -> Result<Self, Self::Error> {
+        if data.is_empty() {
+            return Err(ProtocolError::FileFormatError);
+        }
+
+        Ok(FirmwareImage { data })
+    }
+}
+
+impl TryFrom<&[u8]> for FirmwareImage {
+    type Error = ProtocolError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        Self::try_from(data.to_vec())
+    }
+}
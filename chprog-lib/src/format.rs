@@ -0,0 +1,185 @@
+//! ChProg format file
+//!
+//! Parsers of firmware file formats into flat binary image
+
+use super::definitions::DEFINITIONS;
+use super::ihex;
+use super::protocol::{Protocol, ProtocolError};
+
+/// Largest flash of supported chips in bytes, no firmware image can exceed it
+pub fn max_image_size() -> usize {
+    DEFINITIONS
+        .values()
+        .map(|definition| definition.flash_blocks as usize * Protocol::FLASH_BLOCK_SIZE)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Parse Intel HEX *data* into flat binary image, gaps are filled with 0xFF
+pub fn parse_ihex(data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    let text = ascii_text(data)?;
+
+    let mut image: Vec<u8> = Vec::new();
+    let mut base_addr: usize = 0;
+
+    for record in ihex::records(text) {
+        let record = record.map_err(|_| ProtocolError::FileFormatError)?;
+        match record.record_type {
+            ihex::RECORD_DATA => image_write(&mut image, base_addr + record.offset, &record.data)?,
+            ihex::RECORD_EOF => return Ok(image),
+            // Extended segment or linear address
+            0x02 | 0x04 => {
+                base_addr = record
+                    .base_address()
+                    .ok_or(ProtocolError::FileFormatError)?;
+            }
+            // Start address, not used for flashing
+            _ if record.is_start_address() => {}
+            _ => return Err(ProtocolError::FileFormatError),
+        }
+    }
+
+    // End of file record is missing
+    Err(ProtocolError::FileFormatError)
+}
+
+/// Parse Motorola S-record *data* into flat binary image, gaps are filled with 0xFF
+pub fn parse_srec(data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    let text = ascii_text(data)?;
+
+    let mut image: Vec<u8> = Vec::new();
+
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        // Record: mark, type, count, address, data, checksum
+        let (record_type, hex) = match line.strip_prefix('S') {
+            Some(rest) if rest.len() >= 7 && rest.len() % 2 == 1 => rest.split_at(1),
+            _ => return Err(ProtocolError::FileFormatError),
+        };
+
+        let record = hex_decode(hex)?;
+
+        let count = record[0] as usize;
+        if record.len() != count + 1 {
+            return Err(ProtocolError::FileFormatError);
+        }
+
+        // All record bytes including checksum sum up to 0xFF
+        let checksum = record
+            .iter()
+            .fold(0u8, |checksum, byte| checksum.overflowing_add(*byte).0);
+        if checksum != 0xFF {
+            return Err(ProtocolError::FileFormatError);
+        }
+
+        // Address length depends on record type
+        let address_len = match record_type {
+            // Header and record count
+            "0" | "5" | "6" => continue,
+            "1" | "9" => 2,
+            "2" | "8" => 3,
+            "3" | "7" => 4,
+            _ => return Err(ProtocolError::FileFormatError),
+        };
+
+        if count < address_len + 1 {
+            return Err(ProtocolError::FileFormatError);
+        }
+
+        match record_type {
+            // Data
+            "1" | "2" | "3" => {
+                let address = record[1..=address_len]
+                    .iter()
+                    .fold(0usize, |address, byte| address << 8 | *byte as usize);
+                image_write(&mut image, address, &record[1 + address_len..count])?;
+            }
+            // Termination with start address
+            _ => return Ok(image),
+        }
+    }
+
+    // Termination record is missing
+    Err(ProtocolError::FileFormatError)
+}
+
+// Copy record *payload* to *image* at *address*, growing image up to the largest chip flash
+fn image_write(image: &mut Vec<u8>, address: usize, payload: &[u8]) -> Result<(), ProtocolError> {
+    let end = address + payload.len();
+    let max = max_image_size();
+    if end > max {
+        return Err(ProtocolError::AddressOutOfRange { addr: end, max });
+    }
+
+    if image.len() < end {
+        image.resize(end, 0xFF);
+    }
+    image[address..end].copy_from_slice(payload);
+
+    Ok(())
+}
+
+// Check that *data* is ASCII text
+fn ascii_text(data: &[u8]) -> Result<&str, ProtocolError> {
+    match std::str::from_utf8(data) {
+        Ok(text) if text.is_ascii() => Ok(text),
+        _ => Err(ProtocolError::FileFormatError),
+    }
+}
+
+// Decode string of *hex* digit pairs into bytes
+pub(crate) fn hex_decode(hex: &str) -> Result<Vec<u8>, ProtocolError> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| ProtocolError::FileFormatError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ihex_fills_gaps() {
+        let data = b":020000000102FB\n:020004000304F3\n:00000001FF\n";
+        assert_eq!(
+            parse_ihex(data),
+            Ok(vec![0x01, 0x02, 0xFF, 0xFF, 0x03, 0x04])
+        );
+    }
+
+    #[test]
+    fn parse_srec_reads_data_records() {
+        let data = b"S00600004844521B\nS10500000102F7\nS9030000FC\n";
+
+        assert_eq!(parse_srec(data), Ok(vec![0x01, 0x02]));
+    }
+
+    #[test]
+    fn parse_srec_rejects_address_beyond_flash() {
+        // S3 record at 0x7FFF0000 is far beyond any chip flash
+        let data = b"S3067FFF0000017A\nS70500000000FA\n";
+
+        assert_eq!(
+            parse_srec(data),
+            Err(ProtocolError::AddressOutOfRange {
+                addr: 0x7FFF_0001,
+                max: max_image_size(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_ihex_rejects_extended_address_beyond_flash() {
+        // Extended linear address 0x7FFF0000 is far beyond any chip flash
+        let data = b":020000047FFF7C\n:0100000001FE\n:00000001FF\n";
+
+        assert_eq!(
+            parse_ihex(data),
+            Err(ProtocolError::AddressOutOfRange {
+                addr: 0x7FFF_0001,
+                max: max_image_size(),
+            })
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! ChProg Intel HEX file
+//!
+//! Intel HEX records reading and validation before flashing
+
+use super::format;
+use thiserror::Error;
+
+/// Problem found in Intel HEX record
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum IHexProblem {
+    #[error("malformed record")]
+    Malformed,
+    #[error("checksum mismatch")]
+    ChecksumMismatch,
+    #[error("unsupported record type 0x{0:02X}")]
+    UnsupportedRecord(u8),
+    #[error("duplicate end of file record")]
+    DuplicateEof,
+    #[error("end of file record is missing")]
+    MissingEof,
+    #[error("data at 0x{addr:04X} exceeds flash of {max} bytes")]
+    OutOfRange { addr: usize, max: usize },
+    #[error("data at 0x{addr:04X} overlaps previous record")]
+    Overlap { addr: usize },
+}
+
+/// Intel HEX validation error at *line*
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("line {line}: {problem}")]
+pub struct IHexError {
+    /// Line number starting from 1
+    pub line: usize,
+    pub problem: IHexProblem,
+}
+
+/// Record type of data bytes
+pub(crate) const RECORD_DATA: u8 = 0x00;
+/// Record type of end of file
+pub(crate) const RECORD_EOF: u8 = 0x01;
+
+/// Intel HEX record with valid length and checksum
+pub(crate) struct Record {
+    /// Line number starting from 1
+    pub line: usize,
+    pub record_type: u8,
+    /// Address field, offset from the current base address
+    pub offset: usize,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Base address set by extended segment or linear address record, [None] otherwise
+    pub fn base_address(&self) -> Option<usize> {
+        let value = match self.data[..] {
+            [high, low] => (high as usize) << 8 | low as usize,
+            _ => return None,
+        };
+
+        match self.record_type {
+            0x02 => Some(value << 4),
+            0x04 => Some(value << 16),
+            _ => None,
+        }
+    }
+
+    /// Start address records are not used for flashing
+    pub fn is_start_address(&self) -> bool {
+        matches!(self.record_type, 0x03 | 0x05)
+    }
+}
+
+/// Records of Intel HEX *text* in order, blank lines are skipped
+pub(crate) fn records(text: &str) -> impl Iterator<Item = Result<Record, IHexError>> + '_ {
+    text.lines().enumerate().filter_map(|(index, line)| {
+        let line = line.trim();
+        (!line.is_empty()).then(|| record_parse(index + 1, line))
+    })
+}
+
+// Parse record on *line* with number *line_number*
+fn record_parse(line_number: usize, line: &str) -> Result<Record, IHexError> {
+    let error = |problem| IHexError {
+        line: line_number,
+        problem,
+    };
+
+    // Record: mark, length, address, type, data, checksum
+    let hex = match line.strip_prefix(':') {
+        Some(hex) if hex.len() >= 10 && hex.len() % 2 == 0 && hex.is_ascii() => hex,
+        _ => return Err(error(IHexProblem::Malformed)),
+    };
+
+    let record = format::hex_decode(hex).map_err(|_| error(IHexProblem::Malformed))?;
+
+    let length = record[0] as usize;
+    if record.len() != length + 5 {
+        return Err(error(IHexProblem::Malformed));
+    }
+
+    // All record bytes including checksum sum up to zero
+    let checksum = record
+        .iter()
+        .fold(0u8, |checksum, byte| checksum.overflowing_add(*byte).0);
+    if checksum != 0 {
+        return Err(error(IHexProblem::ChecksumMismatch));
+    }
+
+    Ok(Record {
+        line: line_number,
+        record_type: record[3],
+        offset: (record[1] as usize) << 8 | record[2] as usize,
+        data: record[4..4 + length].to_vec(),
+    })
+}
+
+/// Check that Intel HEX *data* is well-formed and fits into the largest supported chip flash
+pub fn validate(data: &str) -> Result<(), IHexError> {
+    validate_for_flash(data, format::max_image_size())
+}
+
+/// Check that Intel HEX *data* is well-formed and fits into *flash_size* bytes
+pub fn validate_for_flash(data: &str, flash_size: usize) -> Result<(), IHexError> {
+    let mut base_addr: usize = 0;
+    let mut eof_line = None;
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut last_line = 0;
+
+    for record in records(data) {
+        let record = record?;
+        last_line = record.line;
+        let error = |problem| IHexError {
+            line: record.line,
+            problem,
+        };
+
+        match record.record_type {
+            RECORD_DATA => {
+                let address = base_addr + record.offset;
+                let end = address + record.data.len();
+                if end > flash_size {
+                    return Err(error(IHexProblem::OutOfRange {
+                        addr: address,
+                        max: flash_size,
+                    }));
+                }
+
+                if ranges
+                    .iter()
+                    .any(|(start, stop)| address < *stop && *start < end)
+                {
+                    return Err(error(IHexProblem::Overlap { addr: address }));
+                }
+                ranges.push((address, end));
+            }
+            RECORD_EOF => {
+                if eof_line.is_some() {
+                    return Err(error(IHexProblem::DuplicateEof));
+                }
+                eof_line = Some(record.line);
+            }
+            0x02 | 0x04 => {
+                base_addr = record
+                    .base_address()
+                    .ok_or_else(|| error(IHexProblem::Malformed))?;
+            }
+            _ if record.is_start_address() => {}
+            record_type => return Err(error(IHexProblem::UnsupportedRecord(record_type))),
+        }
+    }
+
+    if eof_line.is_none() {
+        return Err(IHexError {
+            line: last_line,
+            problem: IHexProblem::MissingEof,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_skip_blank_lines_and_keep_line_numbers() {
+        let text = ":020000000102FB\n\n:00000001FF\n";
+        let records: Vec<Record> = records(text).collect::<Result<_, _>>().unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].data, [0x01, 0x02]);
+        assert_eq!(records[1].line, 3);
+        assert_eq!(records[1].record_type, RECORD_EOF);
+    }
+
+    #[test]
+    fn validate_accepts_well_formed_file() {
+        assert_eq!(validate(":020000000102FB\n:00000001FF\n"), Ok(()));
+    }
+
+    #[test]
+    fn validate_reports_problem_line() {
+        assert_eq!(
+            validate(":020000000102FB\n:020000000102FC\n:00000001FF\n"),
+            Err(IHexError {
+                line: 2,
+                problem: IHexProblem::ChecksumMismatch,
+            })
+        );
+        assert_eq!(
+            validate(":020000000102FB\n:0100010003FB\n:00000001FF\n"),
+            Err(IHexError {
+                line: 2,
+                problem: IHexProblem::Overlap { addr: 1 },
+            })
+        );
+        assert_eq!(
+            validate(":020000000102FB\n"),
+            Err(IHexError {
+                line: 1,
+                problem: IHexProblem::MissingEof,
+            })
+        );
+    }
+
+    #[test]
+    fn validate_for_flash_checks_size() {
+        assert_eq!(
+            validate_for_flash(":020004000304F3\n:00000001FF\n", 5),
+            Err(IHexError {
+                line: 1,
+                problem: IHexProblem::OutOfRange { addr: 4, max: 5 },
+            })
+        );
+    }
+}
@@ -9,61 +9,246 @@
 //! - Reset chip to bootloader using DTR and RTS lines
 //! - Erase flash memory on chip
 //! - Detect chip type
-//! - Flash firmware file to chip
+//! - Flash firmware file to chip, binary, Intel HEX or Motorola S-record
 //! - Verify flashed firmware with file
+//! - Find serial ports with connected chip
+//! - Async API with `async` feature
 
-#[macro_use]
-extern crate lazy_static;
-
+#[cfg(feature = "async")]
+pub mod async_api;
 pub mod definitions;
+pub mod error;
+pub mod firmware;
+pub mod format;
+pub mod ihex;
 pub mod protocol;
 pub mod sequence;
+pub mod serial;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
-use protocol::{Protocol, ProtocolError};
-use serial::prelude::*;
-use std::time::Duration;
+use ::serial::prelude::*;
+use error::{AnnotatedError, ProtocolResultExt};
+use protocol::{
+    ChipConfig, ChipInfo, EraseBlockResult, FlashCursor, FlashStats, Mode, ProgramOptions,
+    ProgramReport, ProgressCallback, ProgressEvent, Protocol, ProtocolError, ResetPolarity,
+    SerialErrorKind, V1Config,
+};
+use sequence::Bootloader;
+use std::io;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
 /// Chip firmware operations stucture
 pub struct ChProg {
     protocol: Protocol,
+    operation_timeout: Duration,
 }
 
-impl ChProg {
-    /// Creates new ChProg instance, opens specified [serial_port]
-    /// and do initial serial setup
-    pub fn new(serial_port: String) -> Result<Self, ProtocolError> {
+/// Builder for [ChProg] with configurable serial parameters
+pub struct ChProgBuilder {
+    port: Option<String>,
+    baud_rate: ::serial::BaudRate,
+    timeout: Duration,
+    max_retries: u8,
+    preferred_bootloader: Bootloader,
+    reset_polarity: ResetPolarity,
+    no_reset: bool,
+    connect_retries: Option<u32>,
+    total_operation_timeout: Duration,
+}
+
+impl Default for ChProgBuilder {
+    fn default() -> Self {
+        ChProgBuilder {
+            port: None,
+            baud_rate: ::serial::Baud57600,
+            timeout: Protocol::DEFAULT_TIMEOUT,
+            max_retries: Protocol::DEFAULT_MAX_RETRIES,
+            preferred_bootloader: Bootloader::Unknown,
+            reset_polarity: ResetPolarity::CH55X_STANDARD,
+            no_reset: false,
+            connect_retries: None,
+            total_operation_timeout: ChProg::DEFAULT_OPERATION_TIMEOUT,
+        }
+    }
+}
+
+impl ChProgBuilder {
+    /// Serial port [name] to open, required
+    pub fn port(mut self, name: impl Into<String>) -> Self {
+        self.port = Some(name.into());
+        self
+    }
+
+    /// Serial port baud [rate]
+    pub fn baud_rate(mut self, rate: ::serial::BaudRate) -> Self {
+        self.baud_rate = rate;
+        self
+    }
+
+    /// Serial read timeout [dur]
+    pub fn timeout(mut self, dur: Duration) -> Self {
+        self.timeout = dur;
+        self
+    }
+
+    /// Request resend attempts after transport error
+    pub fn max_retries(mut self, n: u8) -> Self {
+        self.max_retries = n;
+        self
+    }
+
+    /// Restrict chip detection to specified [bootloader] version
+    pub fn preferred_bootloader(mut self, bootloader: Bootloader) -> Self {
+        self.preferred_bootloader = bootloader;
+        self
+    }
+
+    /// Reset lines [polarity] of the board
+    pub fn reset_polarity(mut self, polarity: ResetPolarity) -> Self {
+        self.reset_polarity = polarity;
+        self
+    }
+
+    /// Deassert reset lines right after opening port, so the chip
+    /// already in bootloader mode is not reset by adapter asserting DTR
+    pub fn no_reset(mut self, no_reset: bool) -> Self {
+        self.no_reset = no_reset;
+        self
+    }
+
+    /// Detect bootloader up to [n] times while building, chip is reset
+    /// before each attempt unless [no_reset] is set
+    pub fn connect_retries(mut self, n: u32) -> Self {
+        self.connect_retries = Some(n);
+        self
+    }
+
+    /// Abort erase, flash and verify not completed within [dur]
+    pub fn total_operation_timeout(mut self, dur: Duration) -> Self {
+        self.total_operation_timeout = dur;
+        self
+    }
+
+    /// Open serial port and do initial serial setup
+    pub fn build(mut self) -> Result<ChProg, ProtocolError> {
+        let serial_port = match self.port.take() {
+            Some(serial_port) => serial_port,
+            None => {
+                return Err(ProtocolError::ConfigurationError(
+                    "serial port is not set".to_string(),
+                ))
+            }
+        };
+
         // Try to open serial port
-        let port_result = serial::open(&serial_port);
+        let port_result = ::serial::open(&serial_port);
         if port_result.is_err() {
-            return Err(ProtocolError::SerialError);
+            return Err(ProtocolError::SerialError {
+                port: serial_port,
+                kind: SerialErrorKind::Open,
+            });
         }
 
-        // Following setup procedure
-        let mut port_box = Box::new(port_result.unwrap());
+        self.build_with_port(Box::new(port_result.unwrap()), serial_port)
+    }
 
+    // Do initial setup of opened *port_box* named *serial_port*
+    fn build_with_port(
+        self,
+        mut port_box: Box<dyn SerialPort + Send>,
+        serial_port: String,
+    ) -> Result<ChProg, ProtocolError> {
         // Set timeout
-        port_box.set_timeout(Duration::from_millis(150)).ok();
+        port_box.set_timeout(self.timeout).ok();
 
         // Set port settings
+        let baud_rate = self.baud_rate;
         let port_setup = port_box.reconfigure(&|settings| {
-            settings.set_baud_rate(serial::Baud57600).ok();
-            settings.set_char_size(serial::Bits8);
-            settings.set_parity(serial::ParityNone);
-            settings.set_stop_bits(serial::Stop1);
-            settings.set_flow_control(serial::FlowNone);
+            settings.set_baud_rate(baud_rate).ok();
+            settings.set_char_size(::serial::Bits8);
+            settings.set_parity(::serial::ParityNone);
+            settings.set_stop_bits(::serial::Stop1);
+            settings.set_flow_control(::serial::FlowNone);
 
             Ok(())
         });
 
         if port_setup.is_err() {
-            return Err(ProtocolError::SerialError);
+            return Err(ProtocolError::SerialError {
+                port: serial_port,
+                kind: SerialErrorKind::Open,
+            });
         }
 
-        // Return self
+        let mut protocol = Protocol::new(port_box, serial_port);
+        protocol.set_max_retries(self.max_retries);
+        protocol.set_preferred_bootloader(self.preferred_bootloader);
+        protocol.set_reset_polarity(self.reset_polarity);
+
+        if self.no_reset {
+            // Keep chip in its current mode
+            protocol.reset_lines_release();
+        }
+
+        if let Some(connect_retries) = self.connect_retries {
+            // Reset chip before each detection attempt, chip in bootloader is kept there
+            let attempts = connect_retries.max(1);
+            for attempt in 1..=attempts {
+                if !self.no_reset {
+                    protocol.chip_reset();
+                }
+                match protocol.bootloader_detect() {
+                    Ok(_) => break,
+                    Err(err) if attempt == attempts => return Err(err),
+                    Err(_) => {}
+                }
+            }
+        }
+
+        // Return ChProg
         Ok(ChProg {
-            protocol: Protocol::new(port_box),
+            protocol,
+            operation_timeout: self.total_operation_timeout,
         })
     }
+}
+
+impl ChProg {
+    /// Time given to bootloader to start after reset in [connect]
+    pub const CONNECT_TIMEOUT: Duration = Protocol::BOOTLOADER_START_TIMEOUT;
+
+    /// Time given to complete erase, flash or verify
+    pub const DEFAULT_OPERATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Creates new ChProg instance, opens specified [serial_port]
+    /// and do initial serial setup
+    pub fn new(serial_port: impl AsRef<str>) -> Result<Self, ProtocolError> {
+        ChProgBuilder::default().port(serial_port.as_ref()).build()
+    }
+
+    /// Creates new ChProg instance, opens specified [serial_port]
+    /// and deasserts reset lines to keep chip in bootloader mode
+    pub fn new_no_reset(serial_port: impl AsRef<str>) -> Result<Self, ProtocolError> {
+        ChProgBuilder::default()
+            .port(serial_port.as_ref())
+            .no_reset(true)
+            .build()
+    }
+
+    /// Creates new ChProg instance, opens specified [serial_port]
+    /// and do initial serial setup with [baud] rate
+    pub fn with_baud_rate(
+        serial_port: impl AsRef<str>,
+        baud: ::serial::BaudRate,
+    ) -> Result<Self, ProtocolError> {
+        ChProgBuilder::default()
+            .port(serial_port.as_ref())
+            .baud_rate(baud)
+            .build()
+    }
 
     // High level functions
     /// Execute chip reset sequence
@@ -71,24 +256,409 @@ impl ChProg {
         self.protocol.chip_reset();
     }
 
+    /// Reset chip to bootloader, detect it and return its information
+    pub fn connect(&mut self) -> Result<ChipInfo, ProtocolError> {
+        self.protocol.chip_reset_and_detect()?;
+        self.protocol.chip_detect()?;
+
+        Ok(self.protocol.get_chip_info().clone())
+    }
+
+    /// Close and reopen serial port, chip must be detected again
+    pub fn reconnect(&mut self) -> Result<(), ProtocolError> {
+        self.protocol.reconnect()
+    }
+
+    /// Restrict chip detection to specified [bootloader] version
+    pub fn set_preferred_bootloader(&mut self, bootloader: Bootloader) {
+        self.protocol.set_preferred_bootloader(bootloader);
+    }
+
+    /// Set firmware programming [options]
+    pub fn set_program_options(&mut self, options: ProgramOptions) {
+        self.protocol.set_program_options(options);
+    }
+
+    /// Set V1 bootloader reply codes for bootloader versions that differ from defaults
+    pub fn set_v1_config(&mut self, config: V1Config) {
+        self.protocol.set_v1_config(config);
+    }
+
+    /// Set reset lines [polarity] of the board
+    pub fn set_reset_polarity(&mut self, polarity: ResetPolarity) {
+        self.protocol.set_reset_polarity(polarity);
+    }
+
+    /// Statistics of the last firmware operation
+    pub fn flash_stats(&self) -> FlashStats {
+        self.protocol.flash_stats()
+    }
+
+    /// Report of the last programming operation
+    pub fn program_report(&self) -> &ProgramReport {
+        self.protocol.program_report()
+    }
+
+    /// Set number of request resend attempts after transport error
+    pub fn set_max_retries(&mut self, max_retries: u8) {
+        self.protocol.set_max_retries(max_retries);
+    }
+
+    /// Set [callback] fired with (bytes_done, bytes_total) during [flash] and [verify]
+    pub fn set_progress_callback(&mut self, callback: ProgressCallback) {
+        self.protocol.set_progress_callback(callback);
+    }
+
+    /// Set serial read [timeout], e.g. longer one to wait for slow chip reset
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), ProtocolError> {
+        self.protocol.set_timeout(timeout)
+    }
+
+    /// Set time given to complete erase, flash or verify
+    pub fn set_operation_timeout(&mut self, timeout: Duration) {
+        self.operation_timeout = timeout;
+    }
+
     /// Erase chip flash memory
-    pub fn erase(&mut self) -> Result<(), ProtocolError> {
-        self.protocol.erase()
+    pub fn erase(&mut self) -> Result<Vec<EraseBlockResult>, ProtocolError> {
+        self.bootloader_check()?;
+        self.guarded(|protocol| protocol.erase())
+    }
+
+    /// Erase single flash block at [block_index]
+    pub fn erase_block(&mut self, block_index: u8) -> Result<(), ProtocolError> {
+        self.bootloader_check()?;
+        self.guarded(|protocol| protocol.erase_block(block_index))
+    }
+
+    /// Erase [count] flash blocks starting from block [start]
+    pub fn erase_range(&mut self, start: u8, count: u8) -> Result<(), ProtocolError> {
+        self.bootloader_check()?;
+        self.guarded(|protocol| protocol.erase_range(start, count))
+    }
+
+    /// Write chip [config] option bytes
+    pub fn write_config(&mut self, config: ChipConfig) -> Result<(), ProtocolError> {
+        self.bootloader_check()?;
+        self.protocol.config_write(&config)
+    }
+
+    /// Chip information of the last [detect], chip id is 0 and bootloader is
+    /// [Bootloader::Unknown] until chip is detected
+    pub fn chip_info(&self) -> &ChipInfo {
+        self.protocol.get_chip_info()
+    }
+
+    /// Detect chip and return its information and config,
+    /// config is [None] for V1 bootloader
+    pub fn detect(&mut self) -> Result<(ChipInfo, Option<ChipConfig>), ProtocolError> {
+        self.protocol.bootloader_detect()?;
+        let config = self.protocol.chip_detect()?;
+
+        Ok((self.protocol.get_chip_info().clone(), config))
+    }
+
+    /// Write flash firmware with specified [filename],
+    /// error tells which step of writing failed
+    pub fn flash(
+        &mut self,
+        filename: impl AsRef<Path>,
+    ) -> Result<(), AnnotatedError<ProtocolError>> {
+        self.bootloader_check()
+            .with_context("while writing firmware")?;
+        self.guarded(|protocol| protocol.write(filename))
+    }
+
+    /// Write flash firmware with specified [filename] calling [on_progress]
+    /// with (bytes_done, bytes_total) after each written and verified packet
+    pub fn flash_with_progress<F: FnMut(usize, usize)>(
+        &mut self,
+        filename: impl AsRef<Path>,
+        on_progress: F,
+    ) -> Result<(), AnnotatedError<ProtocolError>> {
+        self.bootloader_check()
+            .with_context("while writing firmware")?;
+        self.guarded(|protocol| protocol.write_with_progress(filename, on_progress))
+    }
+
+    /// Write flash firmware from *data* yielding progress events
+    ///
+    /// Each call to `next()` performs the next step of the operation: erase,
+    /// a single written or verified packet, or bootloader exit. Events of a step
+    /// are buffered in an internal channel until yielded.
+    /// Iteration ends with `None` on success or yields an `Err` on failure,
+    /// operation timeout runs from the iterator creation until it is dropped
+    pub fn flash_progress_iter(
+        &mut self,
+        data: Arc<[u8]>,
+    ) -> impl Iterator<Item = Result<ProgressEvent, ProtocolError>> + '_ {
+        let (sender, receiver) = mpsc::channel();
+        let stage = match self.bootloader_check() {
+            Ok(()) => FlashStage::Prepare,
+            Err(err) => {
+                sender.send(Err(err)).ok();
+                FlashStage::Done
+            }
+        };
+
+        self.protocol
+            .set_deadline(Some(Instant::now() + self.operation_timeout));
+
+        FlashProgressIter {
+            protocol: &mut self.protocol,
+            data,
+            stage,
+            sender,
+            receiver,
+        }
     }
 
-    /// Detect chip
-    pub fn detect(&mut self) -> Result<(), ProtocolError> {
-        self.protocol.bootloader_detect();
-        self.protocol.chip_detect()
+    /// Write flash firmware from already loaded [data]
+    pub fn flash_bytes(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        self.bootloader_check()?;
+        self.guarded(|protocol| protocol.flash_bytes(data))
     }
 
-    /// Write flash firmware with specified [filename]
-    pub fn flash(&mut self, filename: String) -> Result<(), ProtocolError> {
-        self.protocol.write(filename)
+    /// Verify flash firmware with specified [filename], binary, Intel HEX or S-record
+    pub fn verify(&mut self, filename: impl AsRef<Path>) -> Result<(), ProtocolError> {
+        self.bootloader_check()?;
+        self.guarded(|protocol| protocol.verify(filename))
     }
 
-    /// Verify flash firmware with specified [filename]
-    pub fn verify(&mut self, filename: String) -> Result<(), ProtocolError> {
-        self.protocol.verify(filename)
+    /// Verify flash firmware with specified [filename] calling [on_progress]
+    /// with (bytes_done, bytes_total) after each verified packet
+    pub fn verify_with_progress<F: FnMut(usize, usize)>(
+        &mut self,
+        filename: impl AsRef<Path>,
+        on_progress: F,
+    ) -> Result<(), ProtocolError> {
+        self.bootloader_check()?;
+        self.guarded(|protocol| protocol.verify_with_progress(filename, on_progress))
+    }
+
+    /// Check that Intel HEX file at [path] is well-formed and fits into detected chip
+    pub fn validate_hex_file(&self, path: &Path) -> Result<(), ProtocolError> {
+        self.bootloader_check()?;
+        self.protocol.validate_hex_file(path)
+    }
+
+    /// Verify flash firmware with already loaded [data]
+    pub fn verify_bytes(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        self.bootloader_check()?;
+        self.guarded(|protocol| protocol.verify_bytes(data))
+    }
+
+    // Run firmware *operation* aborting it after operation timeout
+    fn guarded<T, E>(
+        &mut self,
+        operation: impl FnOnce(&mut Protocol) -> Result<T, E>,
+    ) -> Result<T, E> {
+        self.protocol
+            .set_deadline(Some(Instant::now() + self.operation_timeout));
+        let result = operation(&mut self.protocol);
+        self.protocol.set_deadline(None);
+
+        result
+    }
+
+    // Check that chip was detected in bootloader mode
+    fn bootloader_check(&self) -> Result<(), ProtocolError> {
+        if !self.protocol.get_chip_info().is_in_bootloader() {
+            return Err(ProtocolError::ChipNotInBootloader);
+        }
+
+        Ok(())
+    }
+}
+
+/// Step of [ChProg::flash_progress_iter] operation
+enum FlashStage {
+    Prepare,
+    Write(FlashCursor<io::Cursor<Arc<[u8]>>>),
+    Verify(FlashCursor<io::Cursor<Arc<[u8]>>>),
+    Exit,
+    Done,
+}
+
+/// Iterator performing firmware write step by step
+struct FlashProgressIter<'a> {
+    protocol: &'a mut Protocol,
+    data: Arc<[u8]>,
+    stage: FlashStage,
+    sender: mpsc::Sender<Result<ProgressEvent, ProtocolError>>,
+    receiver: mpsc::Receiver<Result<ProgressEvent, ProtocolError>>,
+}
+
+impl FlashProgressIter<'_> {
+    // Perform the current stage and move to the next one
+    fn step(&mut self) -> Result<(), ProtocolError> {
+        let protocol = &mut *self.protocol;
+        let sender = &self.sender;
+        let on_progress = &mut |event| {
+            sender.send(Ok(event)).ok();
+        };
+
+        self.stage = match std::mem::replace(&mut self.stage, FlashStage::Done) {
+            FlashStage::Prepare => {
+                protocol.write_prepare(self.data.len())?;
+                on_progress(ProgressEvent::Erased);
+                FlashStage::Write(protocol.flash_buffer_cursor(self.data.clone(), Mode::Write)?)
+            }
+            FlashStage::Write(mut cursor) => {
+                if protocol.flash_step(&mut cursor, on_progress)? {
+                    FlashStage::Write(cursor)
+                } else {
+                    FlashStage::Verify(
+                        protocol.flash_buffer_cursor(self.data.clone(), Mode::Verify)?,
+                    )
+                }
+            }
+            FlashStage::Verify(mut cursor) => {
+                if protocol.flash_step(&mut cursor, on_progress)? {
+                    FlashStage::Verify(cursor)
+                } else {
+                    FlashStage::Exit
+                }
+            }
+            FlashStage::Exit => {
+                protocol.bootloader_exit()?;
+                FlashStage::Done
+            }
+            FlashStage::Done => FlashStage::Done,
+        };
+
+        Ok(())
+    }
+}
+
+impl Iterator for FlashProgressIter<'_> {
+    type Item = Result<ProgressEvent, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Ok(event) = self.receiver.try_recv() {
+                return Some(event);
+            }
+
+            if let FlashStage::Done = self.stage {
+                return None;
+            }
+
+            if let Err(err) = self.step() {
+                self.stage = FlashStage::Done;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+impl Drop for FlashProgressIter<'_> {
+    fn drop(&mut self) {
+        self.protocol.set_deadline(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::MockSerialPort;
+
+    #[test]
+    fn builder_forwards_baud_rate_to_port() {
+        let port = MockSerialPort::new();
+        let chprog = ChProgBuilder::default()
+            .baud_rate(::serial::Baud115200)
+            .build_with_port(Box::new(port.clone()), "mock".to_string());
+
+        assert!(chprog.is_ok());
+        assert_eq!(port.state().settings.baud_rate, ::serial::Baud115200);
+    }
+
+    #[test]
+    fn builder_sets_timeout_on_port() {
+        let port = MockSerialPort::new();
+        let chprog = ChProgBuilder::default()
+            .timeout(Duration::from_millis(500))
+            .build_with_port(Box::new(port.clone()), "mock".to_string());
+
+        assert!(chprog.is_ok());
+        assert_eq!(port.state().timeout, Duration::from_millis(500));
+    }
+
+    // ChProg with CH552 in V1 bootloader accepting every request
+    fn v1_chprog() -> (ChProg, MockSerialPort) {
+        let port = MockSerialPort::new();
+        port.respond_with(|frame| {
+            let payload: &[u8] = match frame[protocol::PREAMBLE_LEN] {
+                0xA1 | 0xA2 => &[0x52, 0x11],
+                0xBB => &[0x21, 0x03],
+                _ => &[0x00],
+            };
+
+            let mut reply = protocol::REPLY_PREAMBLE.to_vec();
+            reply.extend_from_slice(payload);
+            reply.push(protocol::packet_checksum(payload));
+            reply
+        });
+
+        let chprog = ChProgBuilder::default()
+            .build_with_port(Box::new(port.clone()), "mock".to_string())
+            .unwrap();
+        (chprog, port)
+    }
+
+    #[test]
+    fn flash_progress_iter_sends_packet_per_step() {
+        let (mut chprog, port) = v1_chprog();
+        chprog.detect().unwrap();
+        let detect_requests = port.state().sent_packets.len();
+
+        let mut events = chprog.flash_progress_iter(vec![0x02; 64].into());
+
+        // Erase request and 14 blocks of CH552
+        assert!(matches!(events.next(), Some(Ok(ProgressEvent::Erased))));
+        assert_eq!(port.state().sent_packets.len(), detect_requests + 15);
+
+        assert!(matches!(
+            events.next(),
+            Some(Ok(ProgressEvent::WritePacket {
+                address: 0,
+                length: 60
+            }))
+        ));
+        assert_eq!(port.state().sent_packets.len(), detect_requests + 16);
+
+        // Rest of write, verify and bootloader exit
+        assert!(events.all(|event| event.is_ok()));
+        assert_eq!(port.state().sent_packets.len(), detect_requests + 20);
+    }
+
+    #[test]
+    fn flash_reports_failed_step() {
+        let (mut chprog, port) = v1_chprog();
+
+        let err = chprog.flash("firmware.bin").unwrap_err();
+        assert_eq!(*err, ProtocolError::ChipNotInBootloader);
+        assert!(err.to_string().starts_with("while writing firmware: "));
+
+        chprog.detect().unwrap();
+        let err = chprog
+            .flash_with_progress("missing/firmware.bin", |_, _| {})
+            .unwrap_err();
+        assert_eq!(err.into_inner(), ProtocolError::FileAccessError);
+        assert_eq!(port.state().sent_packets.len(), 3);
+    }
+
+    #[test]
+    fn flash_progress_iter_requires_detected_chip() {
+        let (mut chprog, port) = v1_chprog();
+        let mut events = chprog.flash_progress_iter(vec![0x02; 64].into());
+
+        assert_eq!(
+            events.next().map(|event| event.map(|_| ())),
+            Some(Err(ProtocolError::ChipNotInBootloader))
+        );
+        assert!(events.next().is_none());
+        assert!(port.state().sent_packets.is_empty());
     }
 }
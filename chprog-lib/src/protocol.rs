@@ -2,14 +2,21 @@
 //!
 //! Basic logic of working with the microcontroller
 
-use super::definitions::DEFINITIONS;
-use super::sequence::{Bootloader, SEQUENCES};
+use super::definitions::{ChipFamily, Definition, DEFINITIONS};
+use super::error::{AnnotatedError, IoError, ProtocolResultExt};
+use super::firmware::FirmwareImage;
+use super::format;
+use super::ihex;
+use super::sequence::{Bootloader, Sequence, SEQUENCES};
+use log::{debug, info, warn};
 use rand::Rng;
 use serial::prelude::*;
-use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
-use std::time::Duration;
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 /// Firmware flashing mode
@@ -18,82 +25,602 @@ pub enum Mode {
     Verify,
 }
 
+/// Levels of DTR (RST) and RTS (BOOT) lines asserted during chip reset
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResetPolarity {
+    pub dtr_active_high: bool,
+    pub rts_active_high: bool,
+}
+
+impl ResetPolarity {
+    /// Both lines active high, as wired on common CH55x boards
+    pub const CH55X_STANDARD: ResetPolarity = ResetPolarity {
+        dtr_active_high: true,
+        rts_active_high: true,
+    };
+}
+
+impl Default for ResetPolarity {
+    fn default() -> Self {
+        Self::CH55X_STANDARD
+    }
+}
+
+/// V1 bootloader reply codes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct V1Config {
+    /// Status code of a matching verify packet, 0x00 by default
+    pub verify_success_code: u8,
+}
+
+/// Firmware programming options
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProgramOptions {
+    /// Skip writing packets of blank (0xFF) bytes, flash is already erased there
+    pub skip_blank_pages: bool,
+    /// Resend attempts for a failed packet before aborting operation
+    pub packet_retries: u8,
+}
+
+/// Statistics of the last firmware operation
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FlashStats {
+    /// Packets sent to chip
+    pub total_packets: u32,
+    /// Resent packets after failure
+    pub packets_resent: u32,
+    /// Requests resent after transport error, nonzero hints at marginal connection
+    pub packets_retried: u32,
+}
+
+impl FlashStats {
+    /// Share of packets sent without transport retries, 1.0 when nothing was sent
+    pub fn reliability_score(&self) -> f32 {
+        if self.total_packets == 0 {
+            return 1.0;
+        }
+
+        // Retries of detect and erase requests also count
+        (1.0 - self.packets_retried as f32 / self.total_packets as f32).max(0.0)
+    }
+}
+
+/// Result of erasing one flash block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EraseBlockResult {
+    /// Index of erased block, always 0 for bulk erase
+    pub block_index: u8,
+    /// Block erased successfully
+    pub success: bool,
+    /// Status code replied by chip on failure
+    pub error_code: Option<u8>,
+}
+
+/// Report of the last programming operation
+#[derive(Clone, Debug, Default)]
+pub struct ProgramReport {
+    /// Per block results of the last erase
+    pub erase_results: Vec<EraseBlockResult>,
+}
+
+/// Callback receiving firmware operation progress as (bytes_done, bytes_total)
+pub type ProgressCallback = Box<dyn Fn(usize, usize) + Send>;
+
+/// Firmware operation progress
+#[derive(Debug)]
+pub enum ProgressEvent {
+    /// Flash erased
+    Erased,
+    /// Packet of *length* bytes written at *address*
+    WritePacket { address: usize, length: usize },
+    /// Packet of *length* bytes verified at *address*
+    VerifyPacket { address: usize, length: usize },
+    /// Estimated *seconds* left to finish writing, follows every `WritePacket`
+    TimeRemaining { seconds: f32 },
+}
+
+/// Algorithm used to calculate a checksum byte injected into firmware
+pub enum ChecksumInjectAlgo {
+    /// Sum of all other bytes XOR'd with 0xFF
+    SumXorAll,
+    /// CRC-8 (polynomial 0x07) of all other bytes
+    Crc8,
+}
+
+impl ChecksumInjectAlgo {
+    /// Calculate checksum of *data* skipping the byte at *offset*
+    pub fn compute(&self, data: &[u8], offset: usize) -> u8 {
+        let bytes = data
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != offset)
+            .map(|(_, byte)| *byte);
+
+        match self {
+            ChecksumInjectAlgo::SumXorAll => {
+                bytes.fold(0u8, |checksum, byte| checksum.overflowing_add(byte).0) ^ 0xFF
+            }
+            ChecksumInjectAlgo::Crc8 => bytes.fold(0u8, |mut crc, byte| {
+                crc ^= byte;
+                for _ in 0..8 {
+                    if crc & 0x80 != 0 {
+                        crc = (crc << 1) ^ 0x07;
+                    } else {
+                        crc <<= 1;
+                    }
+                }
+                crc
+            }),
+        }
+    }
+}
+
 /// Possible errors while using library
-#[derive(Error, Debug)]
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
 pub enum ProtocolError {
     #[error("Preamble mismatch")]
     PreableMismatch,
     #[error("Checksum mismatch")]
     ChecksumMismatch,
-    #[error("Serial timeout")]
-    SerialTimeout,
-    #[error("Serial error")]
-    SerialError,
+    #[error("Serial error on port {port}: {kind}")]
+    SerialError { port: String, kind: SerialErrorKind },
     #[error("File access error")]
     FileAccessError,
     #[error("File format error")]
     FileFormatError,
-    #[error("Bootloader unknown")]
+    #[error("Bootloader unknown, operation requires detected bootloader; call detect() first")]
     BootloaderUnknown,
-    #[error("Chip unknown")]
-    ChipUnknown,
+    #[error("Bootloader not supported yet")]
+    BootloaderNotSupported,
+    #[error(
+        "Detected CH32 bootloader — use a CH32-compatible tool (device type 0x{device_type:02X})"
+    )]
+    ForeignWchIsp { device_type: u8 },
+    #[error("Operation {operation} is not supported by {bootloader} bootloader")]
+    UnsupportedForBootloader {
+        operation: &'static str,
+        bootloader: Bootloader,
+    },
+    #[error("Chip unknown: 0x{chip_id:02X}")]
+    ChipUnknown { chip_id: u8 },
+    #[error("Chip is not in bootloader mode, detect it first")]
+    ChipNotInBootloader,
+    #[error("Address 0x{addr:04X} out of range, flash ends at 0x{max:04X}")]
+    AddressOutOfRange { addr: usize, max: usize },
+    #[error("Address 0x{addr:04X} is not aligned to packet boundary")]
+    MisalignedAddress { addr: u32 },
+    #[error("Firmware of {firmware_size} bytes exceeds flash capacity of {flash_capacity} bytes")]
+    FirmwareTooLarge {
+        firmware_size: usize,
+        flash_capacity: usize,
+    },
+    #[error(
+        "Firmware of {firmware_size} bytes is smaller than {minimum} bytes, file possibly corrupt"
+    )]
+    FileTooSmall {
+        firmware_size: usize,
+        minimum: usize,
+    },
+    #[error("Unexpected reply length: expected {expected}, got {got}")]
+    UnexpectedReplyLength { expected: usize, got: usize },
+    #[error("Verify status unknown: 0x{code:02X}")]
+    VerifyStatusUnknown { code: u8 },
+    #[error("Write failed at address 0x{address:04X} (code 0x{reply_code:02X})")]
+    WriteFailedAt { address: u32, reply_code: u8 },
+    #[error("Verify failed at address 0x{address:04X} in block {block} (code 0x{reply_code:02X})")]
+    VerifyFailedAt {
+        address: u32,
+        block: u8,
+        reply_code: u8,
+    },
+    #[error("Config write failed (code 0x{reply_code:02X})")]
+    ConfigWriteFailed { reply_code: u8 },
+    #[error("Configuration error: {0}")]
+    ConfigurationError(String),
+    #[error("Operation did not complete in time")]
+    OperationTimeout,
+    #[error("Key exchange failed: expected checksum 0x{expected_checksum:02X}, got 0x{got_checksum:02X}. Check that the correct chip ID was detected")]
+    KeyExchangeFailed {
+        expected_checksum: u8,
+        got_checksum: u8,
+    },
+    #[error("Intel HEX validation failed: {0}")]
+    HexValidation(#[from] ihex::IHexError),
+    #[error("I/O error: {0}")]
+    Io(#[source] IoError),
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        ProtocolError::Io(IoError(err))
+    }
+}
+
+/// Kind of failed serial port operation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerialErrorKind {
+    Open,
+    Timeout,
+    ReadFailed,
+    WriteFailed,
+}
+
+impl fmt::Display for SerialErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            SerialErrorKind::Open => "Failed to open",
+            SerialErrorKind::Timeout => "Read timeout",
+            SerialErrorKind::ReadFailed => "Failed to read",
+            SerialErrorKind::WriteFailed => "Failed to write",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// Chip configuration option bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChipConfig {
+    /// Flash write protection word
+    pub wprotect: u32,
+    /// Global configuration word (CFG_WORD): code protection, boot code enable, reset options
+    pub cfg_word: u32,
+    /// Bootloader version digits, read only
+    pub boot_version: (u8, u8, u8),
+    /// Chip unique ID, read only and available with V2 bootloader
+    pub uid: [u8; 8],
+}
+
+impl Default for ChipConfig {
+    /// Values of the built-in V2 config write sequence
+    fn default() -> Self {
+        ChipConfig {
+            wprotect: 0x0000_0003,
+            cfg_word: 0x0000_4EFF,
+            boot_version: (0, 0, 0),
+            uid: [0; 8],
+        }
+    }
+}
+
+impl ChipConfig {
+    /// Bootloader version like "2.40"
+    pub fn boot_version_string(&self) -> String {
+        let (major, minor, patch) = self.boot_version;
+        format!("{}.{}{}", major, minor, patch)
+    }
 }
 
 /// For storing MCU information
+#[derive(Clone, Debug)]
 pub struct ChipInfo {
     pub bootloader: Bootloader,
     pub chip_id: u8,
+    pub bootloader_version: String,
+    // Full V2 config reply with option bytes
+    pub(crate) raw_config: Option<[u8; 30]>,
+    // Full V2 chip detect reply
+    pub(crate) raw_identifier: Option<[u8; 6]>,
+}
+
+/// Builder for [ChipInfo] of a chip that is not detected through [Protocol]
+pub struct ChipInfoBuilder {
+    chip_info: ChipInfo,
+}
+
+impl ChipInfoBuilder {
+    /// Chip [bootloader] version
+    pub fn bootloader(mut self, bootloader: Bootloader) -> Self {
+        self.chip_info.bootloader = bootloader;
+        self
+    }
+
+    /// Chip [id] like 0x52 for CH552
+    pub fn chip_id(mut self, id: u8) -> Self {
+        self.chip_info.chip_id = id;
+        self
+    }
+
+    /// Bootloader [version] string like "2.31"
+    pub fn bootloader_version(mut self, version: impl Into<String>) -> Self {
+        self.chip_info.bootloader_version = version.into();
+        self
+    }
+
+    /// Build chip information without raw replies
+    pub fn build(self) -> ChipInfo {
+        self.chip_info
+    }
+}
+
+impl fmt::Display for ChipInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} bootloader", self.chip_name(), self.bootloader)?;
+        if !self.bootloader_version.is_empty() {
+            write!(f, " {}", self.bootloader_version)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl From<(Bootloader, u8)> for ChipInfo {
+    /// Chip information of *bootloader* and *chip_id* pair
+    fn from((bootloader, chip_id): (Bootloader, u8)) -> Self {
+        ChipInfo::builder()
+            .bootloader(bootloader)
+            .chip_id(chip_id)
+            .build()
+    }
+}
+
+impl ChipInfo {
+    /// Start building chip information, undetected chip by default
+    pub fn builder() -> ChipInfoBuilder {
+        ChipInfoBuilder {
+            chip_info: ChipInfo::undetected(),
+        }
+    }
+
+    /// Chip model name like "CH552", "Unknown" for unsupported chip
+    pub fn chip_name(&self) -> &'static str {
+        ChipFamily::from(self.chip_id).name()
+    }
+
+    /// Full 30 bytes of V2 config reply, [None] for V1 or undetected chip
+    pub fn get_raw_config(&self) -> Option<&[u8; 30]> {
+        self.raw_config.as_ref()
+    }
+
+    /// Full 6 bytes of V2 chip identifier, [None] for V1 or undetected chip
+    pub fn get_raw_identifier(&self) -> Option<&[u8; 6]> {
+        self.raw_identifier.as_ref()
+    }
+
+    // Information of not yet detected chip
+    pub(crate) fn undetected() -> Self {
+        ChipInfo {
+            bootloader: Bootloader::Unknown,
+            chip_id: 0,
+            bootloader_version: String::new(),
+            raw_config: None,
+            raw_identifier: None,
+        }
+    }
+
+    /// Check that chip is detected and is in bootloader mode
+    pub fn is_in_bootloader(&self) -> bool {
+        self.bootloader != Bootloader::Unknown && self.chip_id != 0
+    }
+
+    /// Total on-chip SRAM (xRAM and iRAM) of detected chip in bytes
+    pub fn total_sram_bytes(&self) -> Option<u32> {
+        DEFINITIONS
+            .get(&self.chip_id)
+            .map(|definition| definition.xram_kib as u32 * 1024 + definition.iram_bytes as u32)
+    }
 }
 
 // Current state
 pub struct Protocol {
     chip_info: ChipInfo,
-    port: Box<dyn SerialPort>,
-    pkt_buffer: [u8; Self::PACKET_MAXLEN],
+    port: Box<dyn SerialPort + Send>,
+    port_name: String,
+    port_settings: serial::PortSettings,
+    preferred_bootloader: Option<Bootloader>,
+    options: ProgramOptions,
+    reset_polarity: ResetPolarity,
+    v1_config: V1Config,
+    progress_callback: Option<ProgressCallback>,
+    max_retries: u8,
+    stats: FlashStats,
+    report: ProgramReport,
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) request_log: Option<Vec<crate::testing::RequestLogEntry>>,
     bootkey: [u8; 8],
+    deadline: Option<Instant>,
+    current_timeout: Duration,
 }
 
 impl Protocol {
     /// Maximum request length
     pub const PACKET_MAXLEN: usize = 256;
 
-    /// Create new protocol instance with initial values
-    pub fn new(port: Box<dyn SerialPort>) -> Self {
+    /// Time given to bootloader to start replying after reset
+    pub const BOOTLOADER_START_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Pause between bootloader detect attempts
+    pub const DETECT_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+    /// Serial read timeout
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(150);
+
+    /// Request resend attempts after transport error
+    pub const DEFAULT_MAX_RETRIES: u8 = 3;
+
+    /// Serial read timeout while dropping stale bytes
+    pub const FLUSH_TIMEOUT: Duration = Duration::from_millis(1);
+
+    /// V2 status code accepted for written and verified packets along with success code.
+    /// Its meaning is undocumented, it was seen in replies of some bootloader versions
+    /// and reference tools treat it as success for both writing and verifying
+    pub const V2_STATUS_ACCEPTED: u8 = 0xFE;
+
+    /// Length of random sequence in V2 key input request
+    pub const BOOTKEY_RANDOM_LEN: usize = 48;
+
+    /// Flash block size in bytes
+    pub const FLASH_BLOCK_SIZE: usize = 1024;
+
+    /// Device type reported by CH55x chips in V2 chip detect reply
+    pub const CH55X_DEVICE_TYPE: u8 = 0x11;
+
+    /// Packet address alignment
+    pub const PACKET_ALIGN: u32 = 8;
+
+    /// Create new protocol instance with initial values,
+    /// *port_name* is used for error reporting
+    pub fn new(mut port: Box<dyn SerialPort + Send>, port_name: impl Into<String>) -> Self {
+        let port_settings = Self::port_settings_read(port.as_mut());
+        let current_timeout = port.timeout();
+
         Protocol {
-            chip_info: ChipInfo {
-                bootloader: Bootloader::Unknown,
-                chip_id: 0,
-            },
+            chip_info: ChipInfo::undetected(),
             port,
-            pkt_buffer: [0; Self::PACKET_MAXLEN],
+            port_name: port_name.into(),
+            port_settings,
+            preferred_bootloader: None,
+            options: ProgramOptions::default(),
+            reset_polarity: ResetPolarity::default(),
+            v1_config: V1Config::default(),
+            progress_callback: None,
+            max_retries: Self::DEFAULT_MAX_RETRIES,
+            stats: FlashStats::default(),
+            report: ProgramReport::default(),
+            #[cfg(any(test, feature = "testing"))]
+            request_log: None,
             bootkey: [0; 8],
+            deadline: None,
+            current_timeout,
         }
     }
 
+    /// Detected chip id
+    pub(crate) fn chip_id(&self) -> u8 {
+        self.chip_info.chip_id
+    }
+
+    /// Detected chip information, chip id is 0 and bootloader is
+    /// [Bootloader::Unknown] until chip is detected
+    pub fn get_chip_info(&self) -> &ChipInfo {
+        &self.chip_info
+    }
+
+    /// Restrict bootloader detection to *bootloader* version,
+    /// [Bootloader::Unknown] allows any version
+    pub fn set_preferred_bootloader(&mut self, bootloader: Bootloader) {
+        self.preferred_bootloader = match bootloader {
+            Bootloader::Unknown => None,
+            _ => Some(bootloader),
+        };
+    }
+
+    /// Set firmware programming *options*
+    pub fn set_program_options(&mut self, options: ProgramOptions) {
+        self.options = options;
+    }
+
+    /// Set V1 bootloader reply codes for bootloader versions that differ from defaults
+    pub fn set_v1_config(&mut self, config: V1Config) {
+        self.v1_config = config;
+    }
+
+    /// Set reset lines *polarity* used by [Protocol::chip_reset]
+    pub fn set_reset_polarity(&mut self, polarity: ResetPolarity) {
+        self.reset_polarity = polarity;
+    }
+
+    /// Statistics of the last firmware operation
+    pub fn flash_stats(&self) -> FlashStats {
+        self.stats
+    }
+
+    /// Report of the last programming operation
+    pub fn program_report(&self) -> &ProgramReport {
+        &self.report
+    }
+
+    /// Set number of request resend attempts after transport error,
+    /// 0 fails on the first error
+    pub fn set_max_retries(&mut self, max_retries: u8) {
+        self.max_retries = max_retries;
+    }
+
+    /// Set *callback* fired after each packet of flash or verify operation
+    pub fn set_progress_callback(&mut self, callback: ProgressCallback) {
+        self.progress_callback = Some(callback);
+    }
+
+    /// Set serial read *timeout*, e.g. longer one to wait for slow chip reset
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), ProtocolError> {
+        self.port.set_timeout(timeout).map_err(io::Error::from)?;
+        self.current_timeout = timeout;
+
+        Ok(())
+    }
+
+    /// Serial read timeout used outside of erase and flush
+    pub fn current_timeout(&self) -> Duration {
+        self.current_timeout
+    }
+
+    /// Abort flash, verify and erase loops running past *deadline*
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
     /// Default write firmware procedure
-    pub fn write(&mut self, filename: String) -> Result<(), ProtocolError> {
-        if self.chip_info.bootloader == Bootloader::Unknown {
-            // Detect bootloader
-            self.bootloader_detect();
+    pub fn write(
+        &mut self,
+        filename: impl AsRef<Path>,
+    ) -> Result<(), AnnotatedError<ProtocolError>> {
+        // Load file before erasing chip
+        let data = Self::load_file(filename).with_context("while reading firmware file")?;
+
+        self.flash_bytes(&data)
+            .with_context("while writing firmware")
+    }
 
-            if self.chip_info.bootloader == Bootloader::Unknown {
-                return Err(ProtocolError::BootloaderUnknown);
-            }
-        }
+    /// Write firmware with specified *filename* calling *on_progress*
+    /// with (bytes_done, bytes_total) after each written and verified packet
+    pub fn write_with_progress(
+        &mut self,
+        filename: impl AsRef<Path>,
+        mut on_progress: impl FnMut(usize, usize),
+    ) -> Result<(), AnnotatedError<ProtocolError>> {
+        let data = Self::load_file(filename).with_context("while reading firmware file")?;
+        let total = data.len();
+
+        self.write_buffer_with_progress(&data, &mut |event| {
+            Self::bytes_progress(event, total, &mut on_progress)
+        })
+        .with_context("while writing firmware")
+    }
 
-        if self.chip_info.chip_id == 0 {
-            // Identify chip
-            self.chip_detect()?;
+    /// Write firmware procedure for already loaded *data*
+    pub fn flash_bytes(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        self.write_buffer_with_progress(data, &mut |_| {})
+    }
+
+    /// Write firmware from *data* with checksum byte injected at *offset*
+    pub fn write_with_checksum_injection(
+        &mut self,
+        mut data: Vec<u8>,
+        offset: usize,
+        algo: ChecksumInjectAlgo,
+    ) -> Result<(), ProtocolError> {
+        if offset >= data.len() {
+            return Err(ProtocolError::FileFormatError);
         }
 
-        // Erase chip
-        self.erase()?;
+        // Inject checksum
+        data[offset] = algo.compute(&data, offset);
+
+        self.flash_bytes(&data)
+    }
+
+    /// Write firmware procedure reporting each step to *on_progress*
+    pub fn write_buffer_with_progress(
+        &mut self,
+        file_buffer: &[u8],
+        on_progress: &mut dyn FnMut(ProgressEvent),
+    ) -> Result<(), ProtocolError> {
+        self.write_prepare(file_buffer.len())?;
+        on_progress(ProgressEvent::Erased);
 
         // Write file
-        self.flash_file(filename.clone(), Mode::Write)?;
+        self.flash_buffer(file_buffer, Mode::Write, on_progress)?;
 
         // Verify file
-        self.flash_file(filename, Mode::Verify)?;
+        self.flash_buffer(file_buffer, Mode::Verify, on_progress)?;
 
         // Exit bootloader
         self.bootloader_exit()?;
@@ -101,490 +628,2509 @@ impl Protocol {
         Ok(())
     }
 
+    // Detect chip and erase it before writing *firmware_size* bytes of firmware
+    pub(crate) fn write_prepare(&mut self, firmware_size: usize) -> Result<(), ProtocolError> {
+        self.stats = FlashStats::default();
+
+        // Detect bootloader
+        self.bootloader_detect()?;
+
+        if self.chip_info.chip_id == 0 {
+            // Identify chip
+            self.chip_detect()?;
+        }
+
+        // Check that firmware fits before erasing
+        Self::firmware_size_check(self.chip_info.chip_id, firmware_size)?;
+
+        // Erase chip
+        self.erase()?;
+
+        Ok(())
+    }
+
+    /// Deassert reset (DTR) and boot (RTS) lines leaving chip in its current mode
+    pub fn reset_lines_release(&mut self) {
+        let polarity = self.reset_polarity;
+        self.port.set_dtr(!polarity.dtr_active_high).ok();
+        self.port.set_rts(!polarity.rts_active_high).ok();
+    }
+
     /// Reset MCU to bootloader
     pub fn chip_reset(&mut self) {
         // Sleep 0.01
         std::thread::sleep(Duration::from_millis(10));
 
+        let polarity = self.reset_polarity;
+
         // Set RST(DTR line) & BOOT(RTS line)
-        self.port.set_dtr(true).ok();
-        self.port.set_rts(true).ok();
+        self.port.set_dtr(polarity.dtr_active_high).ok();
+        self.port.set_rts(polarity.rts_active_high).ok();
 
         // Sleep for 0.15
         std::thread::sleep(Duration::from_millis(150));
 
         // Unset RST(DTR line)
-        self.port.set_dtr(false).ok();
+        self.port.set_dtr(!polarity.dtr_active_high).ok();
 
         // Sleep 0.1 & unset BOOT(RTS line) to start bootloader
         std::thread::sleep(Duration::from_millis(100));
-        self.port.set_rts(false).ok();
+        self.port.set_rts(!polarity.rts_active_high).ok();
 
         // Wait 0.25 to settle bootloader
         std::thread::sleep(Duration::from_millis(250));
     }
 
+    /// Reset MCU to bootloader and wait until bootloader replies
+    pub fn chip_reset_and_detect(&mut self) -> Result<Bootloader, ProtocolError> {
+        self.chip_reset();
+
+        // Bootloader detected before reset must reply again
+        self.chip_info = ChipInfo::undetected();
+        self.bootkey = [0; 8];
+
+        self.bootloader_detect_with_timeout(Self::BOOTLOADER_START_TIMEOUT)
+    }
+
     /// Verify firmware on MCU with firmware loaded from file speficied in *filename*
-    pub fn verify(&mut self, filename: String) -> Result<(), ProtocolError> {
+    pub fn verify(&mut self, filename: impl AsRef<Path>) -> Result<(), ProtocolError> {
+        // Format is detected from file contents
+        let image = FirmwareImage::load_from_file(filename.as_ref())?;
+
+        self.verify_bytes(image.as_bytes())
+    }
+
+    /// Verify firmware with specified *filename* calling *on_progress*
+    /// with (bytes_done, bytes_total) after each verified packet
+    pub fn verify_with_progress(
+        &mut self,
+        filename: impl AsRef<Path>,
+        mut on_progress: impl FnMut(usize, usize),
+    ) -> Result<(), ProtocolError> {
+        let image = FirmwareImage::load_from_file(filename.as_ref())?;
+        let total = image.as_bytes().len();
+
+        self.verify_buffer(image.as_bytes(), &mut |event| {
+            Self::bytes_progress(event, total, &mut on_progress)
+        })
+    }
+
+    /// Verify firmware on MCU with already loaded *data*
+    pub fn verify_bytes(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        self.verify_buffer(data, &mut |_| {})
+    }
+
+    // Verify firmware *data* reporting each packet to *on_progress*
+    fn verify_buffer(
+        &mut self,
+        data: &[u8],
+        on_progress: &mut dyn FnMut(ProgressEvent),
+    ) -> Result<(), ProtocolError> {
+        self.stats = FlashStats::default();
+
         // Detect bootloader
-        self.bootloader_detect();
+        self.bootloader_detect()?;
 
         // Identify chip
         self.chip_detect()?;
 
-        // Verify file
-        self.flash_file(filename, Mode::Verify)?;
-
-        Ok(())
+        // Verify data
+        self.flash_buffer(data, Mode::Verify, on_progress)
     }
 
-    /// Erase MCU flash
-    pub fn erase(&mut self) -> Result<(), ProtocolError> {
-        match self.chip_info.bootloader {
-            Bootloader::V1 => {
-                // Send request
-                if self
-                    .request_send(SEQUENCES[&Bootloader::V1].flash_erase)
-                    .is_ok()
-                {
-                    let device_erase_size = DEFINITIONS[&self.chip_info.chip_id].erase_blocks;
-
-                    // Erase each block
-                    for erase_block_index in 0..device_erase_size {
-                        let erase_block_request: [u8; 4] =
-                            [0xA9, 0x02, 0x00, (erase_block_index * 4) as u8];
-
-                        println!("Erasing block: {}", erase_block_index);
-
-                        match self.request_send(&erase_block_request) {
-                            Ok(reply) => {
-                                if reply[0] != 0x00 {
-                                    //println!("ERROR: Erase failed");
-                                    return Err(ProtocolError::ChipUnknown);
-                                }
-                            }
-                            Err(err) => return Err(err),
-                        }
-                    }
+    /// Verify firmware read from *reader* until its end
+    /// keeping only *chunk_size* bytes in memory
+    pub fn verify_streaming(
+        &mut self,
+        reader: impl Read,
+        chunk_size: usize,
+    ) -> Result<(), ProtocolError> {
+        self.stats = FlashStats::default();
 
-                    println!("Flash erased");
-                    return Ok(());
-                }
-            }
-            Bootloader::V2 => {
-                let device_erase_size = DEFINITIONS[&self.chip_info.chip_id].erase_blocks;
-                let mut device_erase_sequence: [u8; 4] = [0; 4];
-
-                // Copy sequence
-                #[allow(clippy::manual_memcpy)]
-                for seq_index in 0..device_erase_sequence.len() {
-                    device_erase_sequence[seq_index] =
-                        SEQUENCES[&Bootloader::V2].flash_erase[seq_index];
-                }
+        // Detect bootloader
+        self.bootloader_detect()?;
 
-                // Insert erase block value from definitions
-                device_erase_sequence[3] = device_erase_size;
+        // Identify chip
+        self.chip_detect()?;
 
-                match self.request_send(&device_erase_sequence) {
-                    Ok(reply) => {
-                        if reply[4] != 0x00 {
-                            //println!("ERROR: Erase failed");
-                            return Err(ProtocolError::ChipUnknown);
-                        }
-                    }
-                    Err(err) => return Err(err),
-                }
+        // Verify stream of unknown size, reader is read forward only
+        self.flash_stream(reader, None, chunk_size, Mode::Verify, &mut |_| {})
+    }
 
-                println!("Flash erased");
-                return Ok(());
-            }
-            Bootloader::Unknown => {
-                //println!("Unknown bootloader");
-                return Err(ProtocolError::BootloaderUnknown);
-            }
-        }
+    /// Check that Intel HEX file at *filename* is well-formed and fits into detected chip
+    pub fn validate_hex_file(&self, filename: impl AsRef<Path>) -> Result<(), ProtocolError> {
+        let flash_capacity = Self::flash_capacity(self.chip_info.chip_id)?;
 
-        Err(ProtocolError::ChipUnknown)
-    }
+        let text = match std::fs::read_to_string(filename) {
+            Ok(text) => text,
+            Err(_) => return Err(ProtocolError::FileAccessError),
+        };
 
-    /// Exit from MCU bootloader
-    pub fn bootloader_exit(&mut self) -> Result<(), ProtocolError> {
-        match self.chip_info.bootloader {
-            Bootloader::Unknown => {
-                //println!("Unknown bootloader");
-                return Err(ProtocolError::BootloaderUnknown);
-            }
-            _ => {
-                // Send request bootloader exit
-                if let Err(err) =
-                    self.request_send(SEQUENCES[&self.chip_info.bootloader].bootloader_exit)
-                {
-                    //println!("Error while sending request");
-                    return Err(err);
-                }
-            }
-        }
+        ihex::validate_for_flash(&text, flash_capacity)?;
 
         Ok(())
     }
 
-    /// Send *sequence* to MCU
-    fn request_send(&mut self, sequence: &[u8]) -> Result<&[u8], ProtocolError> {
-        let mut request: [u8; Self::PACKET_MAXLEN] = [0; Self::PACKET_MAXLEN];
-        let mut request_checksum: u8 = 0;
-        let mut reply_checksum: u8 = 0;
+    /// Erase MCU flash, returning result of each erased block
+    pub fn erase(&mut self) -> Result<Vec<EraseBlockResult>, ProtocolError> {
+        self.erase_blocks(0, None)
+    }
 
-        // Clear buffer
-        self.pkt_buffer = [0; Self::PACKET_MAXLEN];
+    /// Erase single flash block at *block_index*
+    pub fn erase_block(&mut self, block_index: u8) -> Result<(), ProtocolError> {
+        self.erase_range(block_index, 1)
+    }
 
-        // Calculate sequence length
-        let sequence_len = sequence.len();
+    /// Erase *count* flash blocks starting from block *start*,
+    /// V2 bootloader erases from the start of flash only
+    pub fn erase_range(&mut self, start: u8, count: u8) -> Result<(), ProtocolError> {
+        self.erase_blocks(start, Some(count))?;
 
-        // Add preamble
-        request[0] = 0x57;
-        request[1] = 0xAB;
+        Ok(())
+    }
 
-        // Copy sequence
-        for seq_index in 0..sequence_len {
-            request[2 + seq_index] = sequence[seq_index];
+    // Erase *count* blocks from *start*, all chip blocks if *count* is not set
+    fn erase_blocks(
+        &mut self,
+        start: u8,
+        count: Option<u8>,
+    ) -> Result<Vec<EraseBlockResult>, ProtocolError> {
+        self.flush_rx()?;
+        self.report.erase_results.clear();
+        let erase_requests = Self::erase_requests(&self.chip_info, start, count)?;
 
-            // Calculate request checksum
-            request_checksum = request_checksum.overflowing_add(sequence[seq_index]).0;
-        }
+        // Erase takes longer than regular request
+        let timeout = self.port.timeout();
+        self.port
+            .set_timeout(Self::erase_timeout(self.chip_info.chip_id))
+            .map_err(io::Error::from)?;
 
-        // Insert checksum
-        request[2 + sequence_len] = request_checksum;
+        let result = self.erase_flash(&erase_requests);
 
-        // Write serial
-        self.port.write(&request[..=(2 + sequence_len)]).ok();
+        // Restore timeout
+        self.port.set_timeout(timeout).map_err(io::Error::from)?;
 
-        // Read reply from serial until timeout
-        let mut reply_len = 0;
-        while self
-            .port
-            .read_exact(&mut self.pkt_buffer[reply_len..reply_len + 1])
-            .is_ok()
-        {
-            reply_len += 1;
-        }
+        result.map(|_| self.report.erase_results.clone())
+    }
 
-        // Process packet if remote device replied
-        if reply_len == 0 {
-            //println!("ERROR: Serial read timeout");
-            return Err(ProtocolError::SerialTimeout);
+    // Send *erase_requests* with current timeout
+    fn erase_flash(&mut self, erase_requests: &[EraseRequest]) -> Result<(), ProtocolError> {
+        for erase_request in erase_requests {
+            let Some(block_index) = erase_request.block_index else {
+                // Request preceding V1 block erase, its reply is not checked
+                if self.request_send(&erase_request.request).is_err() {
+                    return Err(self.chip_unknown());
+                }
+                continue;
+            };
+
+            Self::deadline_check(self.deadline)?;
+            debug!("Erasing block: {}", block_index);
+
+            let reply = self.request_send(&erase_request.request)?;
+            let result = Self::erase_reply_result(self.chip_info.bootloader, block_index, &reply)?;
+            self.report.erase_results.push(result);
+            if !result.success {
+                warn!("Erase failed");
+                return Err(self.chip_unknown());
+            }
         }
 
-        // Check preamble
-        if (self.pkt_buffer[0] != 0x55) || (self.pkt_buffer[1] != 0xAA) {
-            // Wrong preamble
-            //println!("ERROR: Wrong preamble");
-            return Err(ProtocolError::PreableMismatch);
-        }
+        info!("Flash erased");
+        Ok(())
+    }
 
-        // Calc reply checksum
-        for reply_index in 2..reply_len - 1 {
-            reply_checksum = reply_checksum
-                .overflowing_add(self.pkt_buffer[reply_index])
-                .0;
+    // Requests erasing *count* blocks from *start* of chip with *chip_info*,
+    // all chip blocks if *count* is not set
+    pub(crate) fn erase_requests(
+        chip_info: &ChipInfo,
+        start: u8,
+        count: Option<u8>,
+    ) -> Result<Vec<EraseRequest>, ProtocolError> {
+        let bootloader = chip_info.bootloader;
+        let sequence = Self::bootloader_sequence(bootloader)?;
+
+        let device_erase_size = match DEFINITIONS.get(&chip_info.chip_id) {
+            Some(definition) => definition.erase_blocks,
+            None => {
+                return Err(ProtocolError::ChipUnknown {
+                    chip_id: chip_info.chip_id,
+                })
+            }
+        };
+        if device_erase_size == 0 {
+            // Chip is erased by another bootloader version
+            return Err(ProtocolError::UnsupportedForBootloader {
+                operation: "erase",
+                bootloader,
+            });
         }
 
-        if reply_checksum != self.pkt_buffer[reply_len - 1] {
-            // Checksum error
-            // println!(
-            //     "ERROR: Checksum error {} != {}",
-            //     reply_checksum,
-            //     self.pkt_buffer[reply_len - 1]
-            // );
-            return Err(ProtocolError::ChecksumMismatch);
+        // Erase whole chip by default
+        let count = count.unwrap_or(device_erase_size - start.min(device_erase_size));
+        let end = start as usize + count as usize;
+        if end > device_erase_size as usize {
+            return Err(ProtocolError::AddressOutOfRange {
+                addr: end * Self::FLASH_BLOCK_SIZE,
+                max: device_erase_size as usize * Self::FLASH_BLOCK_SIZE,
+            });
         }
 
-        Ok(&self.pkt_buffer[2..reply_len - 1]) // Exclude preamble and checksum
-    }
-
-    /// Detect bootloader on a connected chip
-    pub fn bootloader_detect(&mut self) {
-        // Check if bootloader is already detected
-        if self.chip_info.bootloader != Bootloader::Unknown {
-            return;
-        }
+        let mut erase_requests = Vec::new();
+        match bootloader {
+            Bootloader::V1 => {
+                // Erase request holds block address in 256 byte units
+                if end * 4 > u8::MAX as usize + 1 {
+                    return Err(ProtocolError::AddressOutOfRange {
+                        addr: end * Self::FLASH_BLOCK_SIZE,
+                        max: (u8::MAX as usize + 1) / 4 * Self::FLASH_BLOCK_SIZE,
+                    });
+                }
 
-        // Send chip detect request
-        if let Ok(reply) = self.request_send(SEQUENCES[&Bootloader::V2].chip_detect) {
-            if reply.len() == 2 {
-                //println!("Detected v1 bootloader");
-                self.chip_info.bootloader = Bootloader::V1;
-                return;
+                erase_requests.push(EraseRequest {
+                    request: sequence.flash_erase.to_vec(),
+                    block_index: None,
+                });
+
+                // Erase each block
+                for erase_block_index in start..(start + count) {
+                    erase_requests.push(EraseRequest {
+                        request: vec![0xA9, 0x02, 0x00, (erase_block_index as u16 * 4) as u8],
+                        block_index: Some(erase_block_index),
+                    });
+                }
             }
+            _ => {
+                if start != 0 {
+                    // Erase command only holds number of blocks
+                    return Err(ProtocolError::UnsupportedForBootloader {
+                        operation: "erase from non-zero block",
+                        bootloader: Bootloader::V2,
+                    });
+                }
+
+                // Insert number of erased blocks
+                let mut device_erase_sequence = sequence.flash_erase[..4].to_vec();
+                device_erase_sequence[3] = count;
 
-            //println!("Detected v2 bootloader");
-            self.chip_info.bootloader = Bootloader::V2;
-            return;
+                erase_requests.push(EraseRequest {
+                    request: device_erase_sequence,
+                    block_index: Some(0),
+                });
+            }
         }
 
-        println!("ERROR: Bootloader not detected");
-        self.chip_info.bootloader = Bootloader::Unknown;
+        Ok(erase_requests)
     }
 
-    /// Detect connected chip
-    pub fn chip_detect(&mut self) -> Result<(), ProtocolError> {
-        match self.chip_info.bootloader {
+    // Result of erasing block *block_index* with *bootloader* replying *reply*
+    pub(crate) fn erase_reply_result(
+        bootloader: Bootloader,
+        block_index: u8,
+        reply: &[u8],
+    ) -> Result<EraseBlockResult, ProtocolError> {
+        let success_code = Self::bootloader_sequence(bootloader)?.success_code;
+        let status_code = match bootloader {
             Bootloader::V1 => {
-                // Identify chip
-                let reply = self.request_send(SEQUENCES[&Bootloader::V1].chip_detect)?;
-                if reply.len() != 2 {
-                    // Unknown chip
-                    return Err(ProtocolError::ChipUnknown);
-                }
-
-                self.chip_info.chip_id = reply[0];
-                println!("Detected chip model: CH5{:02X}", self.chip_info.chip_id);
+                Self::reply_len_check(reply, 1)?;
+                reply[0]
+            }
+            _ => {
+                Self::reply_len_check(reply, 5)?;
+                reply[4]
+            }
+        };
+
+        let success = status_code == success_code;
+        Ok(EraseBlockResult {
+            block_index,
+            success,
+            error_code: (!success).then_some(status_code),
+        })
+    }
+
+    // Read timeout of erase requests to chip *chip_id*
+    pub(crate) fn erase_timeout(chip_id: u8) -> Duration {
+        DEFINITIONS
+            .get(&chip_id)
+            .map_or(Self::DEFAULT_TIMEOUT, |definition| {
+                Duration::from_millis(definition.erase_timeout_ms)
+            })
+    }
+
+    /// Write chip *config* option bytes with V2 bootloader
+    pub fn config_write(&mut self, config: &ChipConfig) -> Result<(), ProtocolError> {
+        let config_write = SEQUENCES
+            .get(&self.chip_info.bootloader)
+            .map_or(&[][..], |sequence| sequence.config_write);
+        if config_write.is_empty() {
+            // Not supported by V1 bootloader
+            return Err(match self.chip_info.bootloader {
+                Bootloader::Unknown => ProtocolError::BootloaderUnknown,
+                bootloader => ProtocolError::UnsupportedForBootloader {
+                    operation: "config write",
+                    bootloader,
+                },
+            });
+        }
+
+        // Keep command header and mask, replace config words
+        let mut request = [0; 17];
+        request[..9].copy_from_slice(&config_write[..9]);
+        request[9..13].copy_from_slice(&config.wprotect.to_le_bytes());
+        request[13..17].copy_from_slice(&config.cfg_word.to_le_bytes());
+
+        let reply = self.request_send(&request)?;
+        Self::reply_len_check(&reply, 5)?;
+
+        let success_code = SEQUENCES[&self.chip_info.bootloader].success_code;
+        if reply[4] != success_code {
+            return Err(ProtocolError::ConfigWriteFailed {
+                reply_code: reply[4],
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Write *data* at *addr* in a single packet without padding or alignment,
+    /// chip must be detected and erased before
+    pub fn write_page_raw(&mut self, addr: u32, data: &[u8]) -> Result<(), ProtocolError> {
+        if !addr.is_multiple_of(Self::PACKET_ALIGN) {
+            return Err(ProtocolError::MisalignedAddress { addr });
+        }
+
+        let definition = match DEFINITIONS.get(&self.chip_info.chip_id) {
+            Some(definition) => *definition,
+            None => return Err(self.chip_unknown()),
+        };
+
+        // Flash above boot address belongs to bootloader
+        let boot_address = definition.boot_address as usize;
+        if addr as usize + data.len() > boot_address {
+            return Err(ProtocolError::AddressOutOfRange {
+                addr: addr as usize + data.len(),
+                max: boot_address,
+            });
+        }
+
+        let (mode_code, max_payload) = match self.chip_info.bootloader {
+            Bootloader::V1 => (
+                SEQUENCES[&Bootloader::V1].mode_write[0],
+                definition.max_packet_payload_v1 as usize,
+            ),
+            Bootloader::V2 => (
+                SEQUENCES[&Bootloader::V2].mode_write[0],
+                definition.max_packet_payload_v2 as usize,
+            ),
+            Bootloader::V3 => return Err(ProtocolError::BootloaderNotSupported),
+            Bootloader::Unknown => return Err(ProtocolError::BootloaderUnknown),
+        };
+
+        if data.len() > max_payload {
+            return Err(ProtocolError::ConfigurationError(format!(
+                "data of {} bytes exceeds packet payload of {} bytes",
+                data.len(),
+                max_payload
+            )));
+        }
+
+        let mut packet: [u8; Self::PACKET_MAXLEN] = [0; Self::PACKET_MAXLEN];
+        let packet_length = match self.chip_info.bootloader {
+            Bootloader::V1 => {
+                packet[0] = mode_code;
+                packet[1] = data.len() as u8;
+                packet[2] = (addr & 0xFF) as u8;
+                packet[3] = ((addr >> 8) & 0xFF) as u8;
+                packet[4..(data.len() + 4)].copy_from_slice(data);
+
+                // Packet is always sent with full payload
+                max_payload + 4
+            }
+            _ => {
+                let header = V2PacketHeader {
+                    command: mode_code,
+                    payload_len: ((data.len() + 5) & 0xFF) as u8,
+                    reserved: 0x00,
+                    addr_lo: (addr & 0xFF) as u8,
+                    addr_hi: ((addr >> 8) & 0xFF) as u8,
+                    reserved2: 0x00,
+                    reserved3: 0x00,
+                    bytes_remaining: (data.len() & 0xFF) as u8,
+                };
+                packet[..8].copy_from_slice(&header.serialize());
+
+                // XOR data with the bootkey
+                for (buffer_index, data_byte) in data.iter().enumerate() {
+                    packet[buffer_index + 8] = data_byte ^ self.bootkey[buffer_index & 0x07];
+                }
+
+                data.len() + 8
+            }
+        };
+
+        self.packet_send(&packet[..packet_length], &Mode::Write, addr as usize)
+    }
+
+    /// Close and reopen serial port with the same settings,
+    /// chip must be detected again after reconnecting
+    pub fn reconnect(&mut self) -> Result<(), ProtocolError> {
+        let timeout = self.current_timeout;
+
+        // Port is opened exclusively, release it first
+        self.port = Box::new(ClosedPort);
+
+        let mut port = match serial::open(&self.port_name) {
+            Ok(port) => port,
+            Err(_) => return Err(self.serial_error(SerialErrorKind::Open)),
+        };
+
+        if port.configure(&self.port_settings).is_err() || port.set_timeout(timeout).is_err() {
+            return Err(self.serial_error(SerialErrorKind::Open));
+        }
+
+        self.port = Box::new(port);
+
+        // Fresh detection handshake is required
+        self.chip_info = ChipInfo::undetected();
+        self.bootkey = [0; 8];
+
+        Ok(())
+    }
+
+    /// Exit from MCU bootloader
+    pub fn bootloader_exit(&mut self) -> Result<(), ProtocolError> {
+        let sequence = Self::bootloader_sequence(self.chip_info.bootloader)?;
+
+        // Send request bootloader exit
+        self.request_send(sequence.bootloader_exit)?;
+
+        Ok(())
+    }
+
+    /// Drop stale bytes left in serial read buffer by previous operation
+    pub fn flush_rx(&mut self) -> Result<(), ProtocolError> {
+        let timeout = self.port.timeout();
+        if self.port.set_timeout(Self::FLUSH_TIMEOUT).is_err() {
+            return Err(self.serial_error(SerialErrorKind::ReadFailed));
+        }
+
+        // Read until timeout
+        let mut byte = [0; 1];
+        while self.port.read_exact(&mut byte).is_ok() {}
+
+        // Restore timeout
+        if self.port.set_timeout(timeout).is_err() {
+            return Err(self.serial_error(SerialErrorKind::ReadFailed));
+        }
+
+        Ok(())
+    }
+
+    /// Send *sequence* to MCU and return reply,
+    /// transport errors are retried up to `max_retries` times
+    fn request_send(&mut self, sequence: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let mut retries = 0;
+        loop {
+            match self.request_send_once(sequence) {
+                Err(err) if Self::request_retryable(&err) && retries < self.max_retries => {
+                    retries += 1;
+                    self.stats.packets_retried += 1;
+
+                    // Drop rest of broken reply before resending
+                    self.flush_rx()?;
+                }
+                result => {
+                    #[cfg(any(test, feature = "testing"))]
+                    self.request_record(sequence, &result);
+
+                    return result;
+                }
+            }
+        }
+    }
+
+    // Check if request failed with transport error *err* is worth resending
+    pub(crate) fn request_retryable(err: &ProtocolError) -> bool {
+        matches!(
+            err,
+            ProtocolError::SerialError { .. }
+                | ProtocolError::PreableMismatch
+                | ProtocolError::ChecksumMismatch
+        )
+    }
+
+    // Append *sequence* and its *result* to request log if enabled
+    #[cfg(any(test, feature = "testing"))]
+    fn request_record(&mut self, sequence: &[u8], result: &Result<Vec<u8>, ProtocolError>) {
+        if let Some(log) = &mut self.request_log {
+            log.push((sequence.to_vec(), result.clone()));
+        }
+    }
+
+    /// Send *sequence* to MCU once and return its reply payload
+    fn request_send_once(&mut self, sequence: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let mut reply: [u8; Self::PACKET_MAXLEN] = [0; Self::PACKET_MAXLEN];
+
+        // Write serial
+        if self.port.write_all(&Self::request_frame(sequence)).is_err() {
+            return Err(self.serial_error(SerialErrorKind::WriteFailed));
+        }
+
+        // Give chip time to prepare reply
+        let response_delay_ms =
+            Self::response_delay_ms(self.chip_info.bootloader, self.preferred_bootloader);
+        if response_delay_ms > 0 {
+            std::thread::sleep(Duration::from_millis(response_delay_ms));
+        }
+
+        // Read available reply bytes until timeout
+        let mut reply_len = 0;
+        while reply_len < reply.len() {
+            match self.port.read(&mut reply[reply_len..]) {
+                Ok(0) | Err(_) => break,
+                Ok(read_len) => reply_len += read_len,
+            }
+        }
+
+        // Process packet if remote device replied
+        if reply_len == 0 {
+            debug!("Serial read timeout");
+            return Err(self.serial_error(SerialErrorKind::Timeout));
+        }
+
+        Self::reply_payload(&reply[..reply_len])
+    }
+
+    // Request frame with preamble and checksum around *sequence*
+    pub(crate) fn request_frame(sequence: &[u8]) -> Vec<u8> {
+        let mut request = Vec::with_capacity(sequence.len() + PREAMBLE_LEN + 1);
+
+        // Add preamble
+        request.extend_from_slice(&REQUEST_PREAMBLE);
+
+        // Copy sequence
+        request.extend_from_slice(sequence);
+
+        // Insert checksum
+        request.push(compute_request_checksum(sequence));
+
+        request
+    }
+
+    // Check preamble and checksum of non-empty *reply* frame and return its payload
+    pub(crate) fn reply_payload(reply: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let reply_len = reply.len();
+
+        // Check preamble
+        if reply_len <= PREAMBLE_LEN || reply[..PREAMBLE_LEN] != REPLY_PREAMBLE {
+            // Wrong preamble
+            warn!("Wrong preamble");
+            return Err(ProtocolError::PreableMismatch);
+        }
+
+        // Calc reply checksum
+        let reply_checksum = compute_reply_checksum(&reply[PREAMBLE_LEN..reply_len - 1]);
+
+        if reply_checksum != reply[reply_len - 1] {
+            // Checksum error
+            warn!(
+                "Checksum error {} != {}",
+                reply_checksum,
+                reply[reply_len - 1]
+            );
+            return Err(ProtocolError::ChecksumMismatch);
+        }
+
+        Ok(reply[PREAMBLE_LEN..reply_len - 1].to_vec()) // Exclude preamble and checksum
+    }
+
+    /// Detect bootloader retrying until *timeout* passes,
+    /// bootloader may not reply right after reset
+    pub fn bootloader_detect_with_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Bootloader, ProtocolError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.bootloader_detect() {
+                Err(_) if Instant::now() < deadline => {
+                    // Fast failures like closed port must not spin
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    std::thread::sleep(Self::DETECT_RETRY_DELAY.min(remaining));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Detect bootloader on a connected chip
+    pub fn bootloader_detect(&mut self) -> Result<Bootloader, ProtocolError> {
+        self.flush_rx()?;
+
+        // Check if bootloader is already detected
+        if self.chip_info.bootloader != Bootloader::Unknown {
+            return Ok(self.chip_info.bootloader);
+        }
+
+        self.try_detect_bootloader_version()
+            .map_err(Self::bootloader_detect_error)
+    }
+
+    // Error of failed bootloader detection caused by *err*
+    pub(crate) fn bootloader_detect_error(err: ProtocolError) -> ProtocolError {
+        match err {
+            // Chip replied, but is flashed with another bootloader or tool
+            ProtocolError::BootloaderNotSupported | ProtocolError::ForeignWchIsp { .. } => err,
+            _ => ProtocolError::BootloaderUnknown,
+        }
+    }
+
+    /// Detect bootloader version on a connected chip, store and return it
+    pub fn try_detect_bootloader_version(&mut self) -> Result<Bootloader, ProtocolError> {
+        self.chip_info.bootloader = Bootloader::Unknown;
+
+        // Use sequence of preferred bootloader only
+        let preferred_bootloader = self.preferred_bootloader;
+        let detect_sequence = Self::detect_sequence(preferred_bootloader)?;
+
+        // Send chip detect request
+        let reply = self.request_send(detect_sequence)?;
+        let bootloader = Self::detect_reply_bootloader(&reply, preferred_bootloader)?;
+
+        self.chip_info.bootloader = bootloader;
+
+        Ok(bootloader)
+    }
+
+    // Chip detect request sent first, restricted to *preferred_bootloader* if set
+    pub(crate) fn detect_sequence(
+        preferred_bootloader: Option<Bootloader>,
+    ) -> Result<&'static [u8], ProtocolError> {
+        match preferred_bootloader {
+            Some(Bootloader::V1) => Ok(SEQUENCES[&Bootloader::V1].chip_detect),
+            Some(Bootloader::V3) => Err(ProtocolError::BootloaderNotSupported),
+            _ => Ok(SEQUENCES[&Bootloader::V2].chip_detect),
+        }
+    }
+
+    // Bootloader version replying chip detect with *reply*,
+    // it must match *preferred_bootloader* if set
+    pub(crate) fn detect_reply_bootloader(
+        reply: &[u8],
+        preferred_bootloader: Option<Bootloader>,
+    ) -> Result<Bootloader, ProtocolError> {
+        let bootloader = if reply.len() == 2 {
+            debug!("Detected v1 bootloader");
+            Bootloader::V1
+        } else if reply.len() == 6 && !Self::device_type_known(reply[4], reply[5]) {
+            // Other WCH-ISP chips reply with their own device type,
+            // only defined ones like CH32V103 are flashed
+            warn!("Detected unsupported device type 0x{:02X}", reply[5]);
+            return Err(ProtocolError::ForeignWchIsp {
+                device_type: reply[5],
+            });
+        } else {
+            debug!("Detected v2 bootloader");
+            Bootloader::V2
+        };
+
+        if preferred_bootloader.is_some() && preferred_bootloader != Some(bootloader) {
+            // Reply of another bootloader version
+            return Err(ProtocolError::BootloaderUnknown);
+        }
+
+        // Reply holds chip id, V3 chips answer V2 detect request
+        let chip_id = match bootloader {
+            Bootloader::V1 => reply.first(),
+            _ => reply.get(4),
+        };
+        if let Some(&chip_id) = chip_id {
+            Self::chip_bootloader_check(chip_id, bootloader)?;
+        }
+
+        Ok(bootloader)
+    }
+
+    /// Detect connected chip and read its config,
+    /// [None] for V1 bootloader which does not report option bytes
+    pub fn chip_detect(&mut self) -> Result<Option<ChipConfig>, ProtocolError> {
+        self.flush_rx()?;
+        let sequence = Self::bootloader_sequence(self.chip_info.bootloader)?;
+
+        // Identify chip
+        let reply = self.request_send(sequence.chip_detect)?;
+        Self::chip_identify(&mut self.chip_info, &reply)?;
+
+        // Read config
+        let reply = self.request_send(sequence.config_read)?;
+        let config = Self::config_apply(&mut self.chip_info, &reply)?;
+
+        if config.is_some() {
+            // Key exchange sets bootkey for V2 packet data, zero key is used otherwise
+            if Self::boot_key_required(self.chip_info.chip_id) {
+                self.key_exchange(&reply)?;
+            } else {
+                self.bootkey = [0; 8];
+            }
+        }
+
+        // Chip parameters are required for further operations
+        Self::chip_defined_check(self.chip_info.chip_id)?;
+
+        Ok(config)
+    }
+
+    // Commands of *bootloader* supported by this library
+    pub(crate) fn bootloader_sequence(
+        bootloader: Bootloader,
+    ) -> Result<&'static Sequence, ProtocolError> {
+        match bootloader {
+            Bootloader::V1 | Bootloader::V2 => Ok(&SEQUENCES[&bootloader]),
+            Bootloader::V3 => Err(ProtocolError::BootloaderNotSupported),
+            Bootloader::Unknown => {
+                debug!("Unknown bootloader");
+                Err(ProtocolError::BootloaderUnknown)
+            }
+        }
+    }
 
-                // Read config
-                let reply = self.request_send(SEQUENCES[&Bootloader::V1].config_read)?;
+    // Store chip id from chip detect *reply* of *chip_info* bootloader
+    pub(crate) fn chip_identify(
+        chip_info: &mut ChipInfo,
+        reply: &[u8],
+    ) -> Result<(), ProtocolError> {
+        match chip_info.bootloader {
+            Bootloader::V1 => {
+                if reply.len() != 2 {
+                    // Unknown chip
+                    return Err(ProtocolError::ChipUnknown {
+                        chip_id: chip_info.chip_id,
+                    });
+                }
+
+                chip_info.chip_id = reply[0];
+            }
+            Bootloader::V2 => {
+                let Ok(raw_identifier) = <[u8; 6]>::try_from(reply) else {
+                    // Unknown chip
+                    return Err(ProtocolError::ChipUnknown {
+                        chip_id: chip_info.chip_id,
+                    });
+                };
+
+                chip_info.raw_identifier = Some(raw_identifier);
+                chip_info.chip_id = reply[4];
+            }
+            Bootloader::V3 => return Err(ProtocolError::BootloaderNotSupported),
+            Bootloader::Unknown => return Err(ProtocolError::BootloaderUnknown),
+        }
+
+        Self::chip_bootloader_check(chip_info.chip_id, chip_info.bootloader)?;
+        info!(
+            "Detected chip model: {}",
+            ChipFamily::from(chip_info.chip_id)
+        );
+
+        Ok(())
+    }
+
+    // Store bootloader version from config read *reply* of *chip_info* bootloader,
+    // return chip config reported by V2 bootloader
+    pub(crate) fn config_apply(
+        chip_info: &mut ChipInfo,
+        reply: &[u8],
+    ) -> Result<Option<ChipConfig>, ProtocolError> {
+        let config = match chip_info.bootloader {
+            Bootloader::V1 => {
                 if reply.len() != 2 {
                     // Unknown bootloader
                     return Err(ProtocolError::BootloaderUnknown);
                 }
 
-                println!(
-                    "Detected bootloader version: {}.{}",
-                    reply[0] >> 4,
-                    reply[1] & 0x0F
-                );
+                chip_info.bootloader_version = Self::v1_version(reply);
+                None
             }
             Bootloader::V2 => {
-                // Random key is a way(guess) to protecting against brute-force flash dump
-                let mut rng = rand::thread_rng();
+                let Ok(raw_config) = <[u8; 30]>::try_from(reply) else {
+                    // Unknown bootloader
+                    warn!("Unexpected bootloader reply length");
+                    return Err(ProtocolError::BootloaderUnknown);
+                };
 
-                // Identify chip
-                let reply = self.request_send(SEQUENCES[&Bootloader::V2].chip_detect)?;
-                if reply.len() != 6 {
-                    // Unknown chip
-                    return Err(ProtocolError::ChipUnknown);
+                chip_info.raw_config = Some(raw_config);
+
+                let config = Self::config_parse(&raw_config);
+                chip_info.bootloader_version = config.boot_version_string();
+                Some(config)
+            }
+            Bootloader::V3 => return Err(ProtocolError::BootloaderNotSupported),
+            Bootloader::Unknown => return Err(ProtocolError::BootloaderUnknown),
+        };
+
+        info!(
+            "Detected bootloader version: {}",
+            chip_info.bootloader_version
+        );
+
+        Ok(config)
+    }
+
+    // Check if V2 bootloader of chip *chip_id* requires key exchange
+    pub(crate) fn boot_key_required(chip_id: u8) -> bool {
+        DEFINITIONS
+            .get(&chip_id)
+            .is_none_or(|definition| definition.boot_key_required)
+    }
+
+    // Check that parameters of chip *chip_id* are defined
+    pub(crate) fn chip_defined_check(chip_id: u8) -> Result<(), ProtocolError> {
+        if !DEFINITIONS.contains_key(&chip_id) {
+            return Err(ProtocolError::ChipUnknown { chip_id });
+        }
+
+        Ok(())
+    }
+
+    // Bootloader version of V1 config read *reply*
+    fn v1_version(reply: &[u8]) -> String {
+        format!("{}.{}", reply[0] >> 4, reply[1] & 0x0F)
+    }
+
+    // Chip config of V2 config read *reply*
+    fn config_parse(reply: &[u8; 30]) -> ChipConfig {
+        // Option bytes are little endian
+        let mut config = ChipConfig {
+            wprotect: u32::from_le_bytes([reply[10], reply[11], reply[12], reply[13]]),
+            cfg_word: u32::from_le_bytes([reply[14], reply[15], reply[16], reply[17]]),
+            boot_version: (reply[19], reply[20], reply[21]),
+            uid: [0; 8],
+        };
+        config.uid.copy_from_slice(&reply[22..30]);
+
+        config
+    }
+
+    // Exchange bootkey with V2 bootloader using *config_reply* of config read request
+    fn key_exchange(&mut self, config_reply: &[u8]) -> Result<(), ProtocolError> {
+        let key_request = KeyRequest::new(self.chip_info.chip_id, config_reply);
+        self.bootkey = key_request.bootkey;
+
+        // Send request
+        let key_reply = self.request_send(&key_request.request)?;
+        key_request.reply_check(&key_reply)
+    }
+
+    // Load firmware binary image from file specified in *filename*
+    pub(crate) fn load_file(filename: impl AsRef<Path>) -> Result<Vec<u8>, ProtocolError> {
+        let file_buffer = match std::fs::read(filename) {
+            Ok(file_buffer) => file_buffer,
+            Err(_) => return Err(ProtocolError::FileAccessError),
+        };
+
+        Self::firmware_parse(file_buffer)
+    }
+
+    // Firmware binary image of binary, Intel HEX or S-record *file_buffer*
+    pub(crate) fn firmware_parse(file_buffer: Vec<u8>) -> Result<Vec<u8>, ProtocolError> {
+        // Detect text formats by record mark
+        match file_buffer.get(..2) {
+            Some([b':', _]) => format::parse_ihex(&file_buffer),
+            Some([b'S', b'0'..=b'9']) => format::parse_srec(&file_buffer),
+            _ => Ok(file_buffer),
+        }
+    }
+
+    // Send firmware from *file_buffer* to MCU flash
+    fn flash_buffer(
+        &mut self,
+        file_buffer: &[u8],
+        mode: Mode,
+        on_progress: &mut dyn FnMut(ProgressEvent),
+    ) -> Result<(), ProtocolError> {
+        self.flash_stream(
+            file_buffer,
+            Some(file_buffer.len()),
+            CHUNK_SIZE,
+            mode,
+            on_progress,
+        )
+    }
+
+    // Send *filesize* bytes of firmware from *reader* to MCU flash,
+    // [None] sends firmware until end of *reader*
+    fn flash_stream(
+        &mut self,
+        reader: impl Read,
+        filesize: Option<usize>,
+        chunk_size: usize,
+        mode: Mode,
+        on_progress: &mut dyn FnMut(ProgressEvent),
+    ) -> Result<(), ProtocolError> {
+        let mut cursor = self.flash_cursor(reader, filesize, chunk_size, mode)?;
+        while self.flash_step(&mut cursor, on_progress)? {}
+
+        debug!("Writing success");
+        Ok(())
+    }
+
+    // Cursor for sending firmware *data* to MCU flash packet by packet
+    pub(crate) fn flash_buffer_cursor(
+        &self,
+        data: Arc<[u8]>,
+        mode: Mode,
+    ) -> Result<FlashCursor<io::Cursor<Arc<[u8]>>>, ProtocolError> {
+        let filesize = data.len();
+        self.flash_cursor(io::Cursor::new(data), Some(filesize), CHUNK_SIZE, mode)
+    }
+
+    // Cursor for sending *filesize* bytes of firmware from *reader* to MCU flash,
+    // [None] sends firmware until end of *reader*
+    fn flash_cursor<R: Read>(
+        &self,
+        reader: R,
+        filesize: Option<usize>,
+        chunk_size: usize,
+        mode: Mode,
+    ) -> Result<FlashCursor<R>, ProtocolError> {
+        FlashCursor::new(reader, filesize, chunk_size, mode, &self.chip_info)
+    }
+
+    // Send the next packet of *cursor* reporting it to *on_progress*,
+    // false when all packets are sent
+    pub(crate) fn flash_step<R: Read>(
+        &mut self,
+        cursor: &mut FlashCursor<R>,
+        on_progress: &mut dyn FnMut(ProgressEvent),
+    ) -> Result<bool, ProtocolError> {
+        let Some(packet) = cursor.packet_next(&self.bootkey, self.options.skip_blank_pages)? else {
+            return Ok(false);
+        };
+
+        Self::deadline_check(self.deadline)?;
+
+        // Send data
+        self.packet_send(&packet.data, &cursor.mode, packet.address)?;
+        on_progress(Self::packet_event(
+            &cursor.mode,
+            packet.address,
+            packet.length,
+        ));
+        if let Mode::Write = cursor.mode {
+            on_progress(cursor.time_remaining());
+        }
+        if let Some(filesize) = cursor.filesize() {
+            self.progress_notify(cursor.bytes_done(), filesize);
+        }
+
+        Ok(true)
+    }
+
+    // Send *packet* for *address* and check reply status,
+    // resend it up to `packet_retries` times on failure other than verify mismatch
+    fn packet_send(
+        &mut self,
+        packet: &[u8],
+        mode: &Mode,
+        address: usize,
+    ) -> Result<(), ProtocolError> {
+        let mut retries = 0;
+        loop {
+            Self::deadline_check(self.deadline)?;
+
+            match self.packet_send_once(packet, mode, address) {
+                Err(err)
+                    if Self::packet_retryable(&err) && retries < self.options.packet_retries =>
+                {
+                    retries += 1;
+                    self.stats.packets_resent += 1;
+
+                    // Drop rest of failed reply before resending
+                    self.flush_rx()?;
                 }
+                result => {
+                    self.stats.total_packets += 1;
+                    return result;
+                }
+            }
+        }
+    }
 
-                self.chip_info.chip_id = reply[4];
-                println!("Detected chip model: CH5{:02X}", self.chip_info.chip_id);
+    // Check if packet failed with *err* is worth resending, verify mismatch is final
+    pub(crate) fn packet_retryable(err: &ProtocolError) -> bool {
+        !matches!(
+            err,
+            ProtocolError::VerifyFailedAt { .. } | ProtocolError::VerifyStatusUnknown { .. }
+        )
+    }
 
-                // Read config
-                let reply = self.request_send(SEQUENCES[&Bootloader::V2].config_read)?;
-                if reply.len() != 30 {
-                    // Unknown bootloader
-                    println!("ERROR: Unexpected bootloader reply length");
-                    return Err(ProtocolError::BootloaderUnknown);
+    // Send *packet* for *address* once and check reply status
+    fn packet_send_once(
+        &mut self,
+        packet: &[u8],
+        mode: &Mode,
+        address: usize,
+    ) -> Result<(), ProtocolError> {
+        let reply = self.request_send(packet)?;
+
+        Self::packet_status_check(
+            self.chip_info.bootloader,
+            mode,
+            &reply,
+            address,
+            &self.v1_config,
+        )
+    }
+
+    // Check status in *reply* to packet sent in *mode* for *address*
+    pub(crate) fn packet_status_check(
+        bootloader: Bootloader,
+        mode: &Mode,
+        reply: &[u8],
+        address: usize,
+        v1_config: &V1Config,
+    ) -> Result<(), ProtocolError> {
+        // Get status code reported for an accepted packet
+        let success_code = SEQUENCES
+            .get(&bootloader)
+            .map_or(0x00, |sequence| sequence.success_code);
+
+        match bootloader {
+            Bootloader::V1 => {
+                Self::reply_len_check(reply, 1)?;
+                if matches!(mode, Mode::Verify) {
+                    if reply[0] != v1_config.verify_success_code {
+                        return Err(ProtocolError::VerifyFailedAt {
+                            address: address as u32,
+                            block: (address / Self::FLASH_BLOCK_SIZE) as u8,
+                            reply_code: reply[0],
+                        });
+                    }
+                } else if reply[0] != 0x00 {
+                    warn!(
+                        "Error while sending data: Write failed at address 0x{:04X}",
+                        address
+                    );
+                    return Err(ProtocolError::WriteFailedAt {
+                        address: address as u32,
+                        reply_code: reply[0],
+                    });
+                }
+            }
+            Bootloader::V2 => {
+                Self::reply_len_check(reply, 5)?;
+                let status_code = reply[4];
+                match mode {
+                    Mode::Write => {
+                        if (status_code != success_code)
+                            && (status_code != Self::V2_STATUS_ACCEPTED)
+                        {
+                            warn!("Error while sending data: Failed at address {}", address);
+                            return Err(ProtocolError::WriteFailedAt {
+                                address: address as u32,
+                                reply_code: status_code,
+                            });
+                        }
+                    }
+                    Mode::Verify => {
+                        if (status_code != success_code)
+                            && (status_code != Self::V2_STATUS_ACCEPTED)
+                        {
+                            // Mismatch codes are not documented, report raw status
+                            warn!("Verify status 0x{:02X} at address {}", status_code, address);
+                            return Err(ProtocolError::VerifyStatusUnknown { code: status_code });
+                        }
+                    }
                 }
+            }
+            Bootloader::V3 => return Err(ProtocolError::BootloaderNotSupported),
+            Bootloader::Unknown => return Err(ProtocolError::BootloaderUnknown),
+        }
+
+        Ok(())
+    }
+
+    // Reply delay of the detected or, before detection, *preferred_bootloader*
+    pub(crate) fn response_delay_ms(
+        bootloader: Bootloader,
+        preferred_bootloader: Option<Bootloader>,
+    ) -> u64 {
+        let bootloader = match bootloader {
+            Bootloader::Unknown => preferred_bootloader.unwrap_or(Bootloader::Unknown),
+            bootloader => bootloader,
+        };
+
+        SEQUENCES
+            .get(&bootloader)
+            .map_or(0, |sequence| sequence.response_delay_ms)
+    }
+
+    // Flash capacity of chip *chip_id* available for firmware in bytes,
+    // flash above boot address belongs to bootloader
+    fn flash_capacity(chip_id: u8) -> Result<usize, ProtocolError> {
+        match DEFINITIONS.get(&chip_id) {
+            Some(definition) => Ok((definition.flash_blocks as usize * Self::FLASH_BLOCK_SIZE)
+                .min(definition.boot_address as usize)),
+            None => Err(ProtocolError::ChipUnknown { chip_id }),
+        }
+    }
+
+    // Check that firmware of *firmware_size* bytes fits into flash of chip *chip_id*
+    // and is not smaller than chip minimum
+    pub(crate) fn firmware_size_check(
+        chip_id: u8,
+        firmware_size: usize,
+    ) -> Result<(), ProtocolError> {
+        let minimum = match DEFINITIONS.get(&chip_id) {
+            Some(definition) => definition.minimum_firmware_bytes,
+            None => return Err(ProtocolError::ChipUnknown { chip_id }),
+        };
+        if firmware_size < minimum {
+            warn!("Firmware bin file possibly corrupt");
+            return Err(ProtocolError::FileTooSmall {
+                firmware_size,
+                minimum,
+            });
+        }
+
+        let flash_capacity = Self::flash_capacity(chip_id)?;
+        if firmware_size > flash_capacity {
+            return Err(ProtocolError::FirmwareTooLarge {
+                firmware_size,
+                flash_capacity,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Unknown chip error for current chip id
+    fn chip_unknown(&self) -> ProtocolError {
+        ProtocolError::ChipUnknown {
+            chip_id: self.chip_info.chip_id,
+        }
+    }
+
+    // Report *bytes_done* of *bytes_total* to progress callback if set
+    fn progress_notify(&self, bytes_done: usize, bytes_total: usize) {
+        if let Some(callback) = &self.progress_callback {
+            callback(bytes_done, bytes_total);
+        }
+    }
+
+    // Read current settings of *port*, defaults are used for unreadable ones
+    fn port_settings_read(port: &mut (dyn SerialPort + Send)) -> serial::PortSettings {
+        let port_settings = Cell::new(serial::PortSettings {
+            baud_rate: serial::Baud57600,
+            char_size: serial::Bits8,
+            parity: serial::ParityNone,
+            stop_bits: serial::Stop1,
+            flow_control: serial::FlowNone,
+        });
+
+        port.reconfigure(&|settings| {
+            let mut current = port_settings.get();
+            current.baud_rate = settings.baud_rate().unwrap_or(current.baud_rate);
+            current.char_size = settings.char_size().unwrap_or(current.char_size);
+            current.parity = settings.parity().unwrap_or(current.parity);
+            current.stop_bits = settings.stop_bits().unwrap_or(current.stop_bits);
+            current.flow_control = settings.flow_control().unwrap_or(current.flow_control);
+            port_settings.set(current);
+
+            Ok(())
+        })
+        .ok();
+
+        port_settings.get()
+    }
+
+    // Check that chip *chip_id* is flashed with *bootloader*,
+    // chips without definition are rejected after detection
+    pub(crate) fn chip_bootloader_check(
+        chip_id: u8,
+        bootloader: Bootloader,
+    ) -> Result<(), ProtocolError> {
+        match DEFINITIONS.get(&chip_id) {
+            Some(definition) if !definition.bootloaders.contains(&bootloader) => {
+                warn!(
+                    "Chip {} is not flashed with {} bootloader",
+                    ChipFamily::from(chip_id),
+                    bootloader
+                );
+                Err(ProtocolError::BootloaderNotSupported)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Check V2 *device_type* against definition of chip *chip_id*,
+    // chips without definition must report CH55x device type
+    fn device_type_known(chip_id: u8, device_type: u8) -> bool {
+        match DEFINITIONS.get(&chip_id) {
+            Some(definition) => definition.device_type == device_type,
+            None => device_type == Self::CH55X_DEVICE_TYPE,
+        }
+    }
+
+    // Serial error of *kind* on the current port
+    fn serial_error(&self, kind: SerialErrorKind) -> ProtocolError {
+        ProtocolError::SerialError {
+            port: self.port_name.clone(),
+            kind,
+        }
+    }
+
+    // Check that *reply* is long enough to access *expected* bytes
+    pub(crate) fn reply_len_check(reply: &[u8], expected: usize) -> Result<(), ProtocolError> {
+        if reply.len() < expected {
+            return Err(ProtocolError::UnexpectedReplyLength {
+                expected,
+                got: reply.len(),
+            });
+        }
+
+        Ok(())
+    }
+
+    // Index of the random request byte used for bootkey calculation
+    fn bootkey_index(seed: u8, divisor: u8, factor: u8) -> usize {
+        let offset = (seed / divisor).saturating_mul(factor) as usize;
+        debug_assert!(
+            offset < Self::BOOTKEY_RANDOM_LEN,
+            "Bootkey index {} is out of random sequence",
+            offset
+        );
+
+        // Keep index inside random sequence
+        3 + offset.min(Self::BOOTKEY_RANDOM_LEN - 1)
+    }
+
+    // Progress event for a packet sent in *mode*
+    fn packet_event(mode: &Mode, address: usize, length: usize) -> ProgressEvent {
+        match mode {
+            Mode::Write => ProgressEvent::WritePacket { address, length },
+            Mode::Verify => ProgressEvent::VerifyPacket { address, length },
+        }
+    }
+
+    // Fail once operation *deadline* has passed
+    pub(crate) fn deadline_check(deadline: Option<Instant>) -> Result<(), ProtocolError> {
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                warn!("Operation timed out");
+                Err(ProtocolError::OperationTimeout)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Pass bytes done of *total* reported by packet *event* to *on_progress*
+    fn bytes_progress(
+        event: ProgressEvent,
+        total: usize,
+        on_progress: &mut impl FnMut(usize, usize),
+    ) {
+        if let ProgressEvent::WritePacket { address, length }
+        | ProgressEvent::VerifyPacket { address, length } = event
+        {
+            on_progress((address + length).min(total), total);
+        }
+    }
 
-                println!(
-                    "Detected bootloader version: {}.{}{}",
-                    reply[19], reply[20], reply[21]
-                );
+    // Estimate time left from average packet time since *started*
+    fn time_remaining(
+        started: Instant,
+        packets_done: usize,
+        total_packets: usize,
+    ) -> ProgressEvent {
+        let elapsed_ms = started.elapsed().as_millis() as f32;
+        let packets_left = total_packets.saturating_sub(packets_done) as f32;
+        ProgressEvent::TimeRemaining {
+            seconds: elapsed_ms / packets_done as f32 * packets_left / 1000.0,
+        }
+    }
+}
 
-                // Key input
-                let mut request: [u8; Self::PACKET_MAXLEN] = [0; Self::PACKET_MAXLEN];
-                request[0] = 0xA3;
-                request[1] = 0x30;
-                request[2] = 0x00;
+/// Erase request and the block it erases
+pub(crate) struct EraseRequest {
+    /// Request sent to bootloader
+    pub(crate) request: Vec<u8>,
+    /// Erased block index, [None] for V1 request preceding block erase
+    pub(crate) block_index: Option<u8>,
+}
 
-                // Checksum
-                let mut checksum: u8 = 0;
-                for reply_byte in reply[22..26].iter() {
-                    checksum = checksum.overflowing_add(*reply_byte).0;
-                }
+/// V2 key input request and the bootkey it sets
+pub(crate) struct KeyRequest {
+    pub(crate) request: [u8; 51],
+    pub(crate) bootkey: [u8; 8],
+    key_checksum: u8,
+}
 
-                // Random sequence
-                for req_index in 0..48 {
-                    let random_byte: u8 = rng.gen();
-                    request[3 + req_index] = random_byte;
-                }
+impl KeyRequest {
+    /// Key input request with random sequence for chip *chip_id*
+    /// using *config_reply* of config read request
+    pub(crate) fn new(chip_id: u8, config_reply: &[u8]) -> Self {
+        // Random key is a way(guess) to protecting against brute-force flash dump
+        let mut rng = rand::thread_rng();
+
+        // Key input
+        let mut request: [u8; 51] = [0; 51];
+        request[0] = 0xA3;
+        request[1] = 0x30;
+        request[2] = 0x00;
+
+        // Checksum
+        let mut checksum: u8 = 0;
+        for reply_byte in config_reply[22..26].iter() {
+            checksum = checksum.overflowing_add(*reply_byte).0;
+        }
 
-                // Calculate the key from the random list
-                self.bootkey[0] = request[(3 + ((request[1] / 7) as u8 * 4)) as usize] ^ checksum;
-                self.bootkey[1] = request[(3 + ((request[1] / 5) as u8)) as usize] ^ checksum;
-                self.bootkey[2] = request[(3 + ((request[1] / 7) as u8)) as usize] ^ checksum;
-                self.bootkey[3] = request[(3 + ((request[1] / 7) as u8 * 6)) as usize] ^ checksum;
-                self.bootkey[4] = request[(3 + ((request[1] / 7) as u8 * 3)) as usize] ^ checksum;
-                self.bootkey[5] = request[(3 + ((request[1] / 5) as u8 * 3)) as usize] ^ checksum;
-                self.bootkey[6] = request[(3 + ((request[1] / 7) as u8 * 5)) as usize] ^ checksum;
-                self.bootkey[7] = self.chip_info.chip_id.overflowing_add(self.bootkey[0]).0;
-
-                // Get key checksum
-                let mut key_checksum: u8 = 0;
-                for key_index in 0..8 {
-                    key_checksum = key_checksum.overflowing_add(self.bootkey[key_index]).0;
-                }
+        // Random sequence
+        for req_index in 0..Protocol::BOOTKEY_RANDOM_LEN {
+            let random_byte: u8 = rng.gen();
+            request[3 + req_index] = random_byte;
+        }
 
-                // Send request
-                let key_reply = self.request_send(&request[0..51])?;
+        // Calculate the key from the random list
+        let seed = request[1];
+        let mut bootkey = [0; 8];
+        bootkey[0] = request[Protocol::bootkey_index(seed, 7, 4)] ^ checksum;
+        bootkey[1] = request[Protocol::bootkey_index(seed, 5, 1)] ^ checksum;
+        bootkey[2] = request[Protocol::bootkey_index(seed, 7, 1)] ^ checksum;
+        bootkey[3] = request[Protocol::bootkey_index(seed, 7, 6)] ^ checksum;
+        bootkey[4] = request[Protocol::bootkey_index(seed, 7, 3)] ^ checksum;
+        bootkey[5] = request[Protocol::bootkey_index(seed, 5, 3)] ^ checksum;
+        bootkey[6] = request[Protocol::bootkey_index(seed, 7, 5)] ^ checksum;
+        bootkey[7] = chip_id.overflowing_add(bootkey[0]).0;
+
+        // Get key checksum
+        let mut key_checksum: u8 = 0;
+        for key_byte in bootkey {
+            key_checksum = key_checksum.overflowing_add(key_byte).0;
+        }
 
-                if key_reply[4] != key_checksum {
-                    // println!(
-                    //     "ERROR: Key checksum error, expected {} got {}",
-                    //     key_checksum, key_reply[4]
-                    // );
+        debug!("Checksum: 0x{:02X}", checksum);
+        debug!("Generated bootkey: {:02X?}", bootkey);
 
-                    return Err(ProtocolError::BootloaderUnknown);
-                }
+        KeyRequest {
+            request,
+            bootkey,
+            key_checksum,
+        }
+    }
 
-                //println!("Checksum: 0x{:02X}", checksum);
-                //println!("Generated bootkey: {:02X?}", self.bootkey);
-            }
-            Bootloader::Unknown => {
-                // Unknown bootloader
-                //println!("ERROR: Unknown bootloader");
-                return Err(ProtocolError::BootloaderUnknown);
-            }
+    /// Check that bootloader *key_reply* confirms the bootkey
+    pub(crate) fn reply_check(&self, key_reply: &[u8]) -> Result<(), ProtocolError> {
+        let key_checksum = self.key_checksum;
+        Protocol::reply_len_check(key_reply, 5)?;
+
+        if key_reply[4] != key_checksum {
+            warn!(
+                "Key checksum error, expected {} got {}",
+                key_checksum, key_reply[4]
+            );
+
+            return Err(ProtocolError::KeyExchangeFailed {
+                expected_checksum: key_checksum,
+                got_checksum: key_reply[4],
+            });
         }
 
         Ok(())
     }
+}
 
-    // Send file to MCU flash
-    fn flash_file(&mut self, filename: String, mode: Mode) -> Result<(), ProtocolError> {
-        if self.chip_info.bootloader == Bootloader::Unknown {
-            //println!("ERROR: Unknown bootloader cannot flash");
-            return Err(ProtocolError::BootloaderUnknown);
-        }
+/// Header of V2 bootloader write and verify packets
+struct V2PacketHeader {
+    command: u8,
+    payload_len: u8,
+    reserved: u8,
+    addr_lo: u8,
+    addr_hi: u8,
+    reserved2: u8,
+    reserved3: u8,
+    bytes_remaining: u8,
+}
 
-        // Try to open specified filename
-        let maybe_fd = File::open(filename);
-        if maybe_fd.is_err() {
-            //println!("ERROR: Cannot open specified file to flash");
-            return Err(ProtocolError::FileAccessError);
-        }
+impl V2PacketHeader {
+    fn serialize(&self) -> [u8; 8] {
+        [
+            self.command,
+            self.payload_len,
+            self.reserved,
+            self.addr_lo,
+            self.addr_hi,
+            self.reserved2,
+            self.reserved3,
+            self.bytes_remaining,
+        ]
+    }
+}
 
-        // File opened, we could safely unwrap here
-        let fd = maybe_fd.unwrap();
-        let mut reader = BufReader::new(fd);
-        let mut file_buffer = Vec::new();
+/// Position of firmware being sent to MCU flash packet by packet
+pub(crate) struct FlashCursor<R: Read> {
+    file_chunk: FileChunk<R>,
+    filesize: Option<usize>,
+    len_bound: Option<usize>,
+    pub(crate) mode: Mode,
+    mode_code: u8,
+    bootloader: Bootloader,
+    definition: Definition,
+    total_packets: usize,
+    packets_done: usize,
+    started: Instant,
+    cur_addr: usize,
+    bytes_done: usize,
+    complete: bool,
+}
 
-        // Read file into u8 vector.
-        if reader.read_to_end(&mut file_buffer).is_err() {
-            //println!("ERROR: Cannot read specified file to flash");
-            return Err(ProtocolError::FileAccessError);
+/// Write or verify packet ready to be sent
+pub(crate) struct FlashPacket {
+    /// Request sent to bootloader
+    pub(crate) data: Vec<u8>,
+    /// Flash address of packet payload
+    pub(crate) address: usize,
+    /// Firmware bytes in packet payload
+    pub(crate) length: usize,
+}
+
+impl<R: Read> FlashCursor<R> {
+    /// Cursor for sending *filesize* bytes of firmware from *reader* in *mode*
+    /// to chip with *chip_info*, [None] reads firmware until end of *reader*
+    pub(crate) fn new(
+        reader: R,
+        filesize: Option<usize>,
+        chunk_size: usize,
+        mode: Mode,
+        chip_info: &ChipInfo,
+    ) -> Result<Self, ProtocolError> {
+        let bootloader = chip_info.bootloader;
+        match bootloader {
+            Bootloader::Unknown => {
+                warn!("Unknown bootloader cannot flash");
+                return Err(ProtocolError::BootloaderUnknown);
+            }
+            Bootloader::V3 => return Err(ProtocolError::BootloaderNotSupported),
+            _ => {}
         }
 
         // Check file size
-        let filesize = file_buffer.len();
-        println!("Firmware filesize: {} bytes", filesize);
-
-        if filesize < 32 {
-            //println!("ERROR: Firmware bin file possibly corrupt.");
-            return Err(ProtocolError::FileFormatError);
+        if let Some(filesize) = filesize {
+            info!("Firmware filesize: {} bytes", filesize);
         }
 
         // Make the buffer length to be on 8 bytes boundary
-        let mut len_bound = filesize;
-        len_bound = len_bound + (len_bound % 8);
+        let len_bound = filesize.map(|filesize| filesize + (filesize % 8));
 
         // Get mode op code
         let mode_code = match mode {
             Mode::Verify => {
-                //println!("Verifying flash...");
-                SEQUENCES[&self.chip_info.bootloader].mode_verify[0]
+                debug!("Verifying flash");
+                SEQUENCES[&bootloader].mode_verify[0]
             }
             Mode::Write => {
-                //println!("Writting flash...");
-                SEQUENCES[&self.chip_info.bootloader].mode_write[0]
+                debug!("Writing flash");
+                SEQUENCES[&bootloader].mode_write[0]
+            }
+        };
+
+        // Packet sizes and flash bounds depend on chip
+        let definition = match DEFINITIONS.get(&chip_info.chip_id) {
+            Some(definition) => *definition,
+            None => {
+                return Err(ProtocolError::ChipUnknown {
+                    chip_id: chip_info.chip_id,
+                })
             }
         };
 
-        // Form packet
-        let mut cur_addr = 0;
-        let mut bytes_to_send = filesize;
-        while cur_addr < len_bound {
-            let mut pkt_length;
-            let mut packet: [u8; 64] = [0; 64];
+        // Packet count to estimate remaining write time
+        let max_payload = match bootloader {
+            Bootloader::V1 => definition.max_packet_payload_v1,
+            _ => definition.max_packet_payload_v2,
+        } as usize;
+
+        Ok(FlashCursor {
+            // Keep only current file chunk in memory
+            file_chunk: FileChunk::new(reader, chunk_size),
+            filesize,
+            len_bound,
+            mode,
+            mode_code,
+            bootloader,
+            definition,
+            total_packets: filesize.map_or(0, |filesize| filesize.div_ceil(max_payload)),
+            packets_done: 0,
+            started: Instant::now(),
+            cur_addr: 0,
+            bytes_done: 0,
+            complete: filesize == Some(0),
+        })
+    }
+
+    /// Next packet to send with V2 data XOR'd with *bootkey*, [None] when all are sent.
+    /// Blank packets of 0xFF bytes are not written with *skip_blank* set
+    pub(crate) fn packet_next(
+        &mut self,
+        bootkey: &[u8; 8],
+        skip_blank: bool,
+    ) -> Result<Option<FlashPacket>, ProtocolError> {
+        while !self.complete
+            && self
+                .len_bound
+                .is_none_or(|len_bound| self.cur_addr < len_bound)
+        {
+            let mut packet: [u8; Protocol::PACKET_MAXLEN] = [0; Protocol::PACKET_MAXLEN];
+
+            // Flash above boot address belongs to bootloader
+            let boot_address = self.definition.boot_address as usize;
+            if self.cur_addr >= boot_address {
+                return Err(ProtocolError::AddressOutOfRange {
+                    addr: self.cur_addr,
+                    max: boot_address,
+                });
+            }
 
-            match self.chip_info.bootloader {
+            let cur_addr = self.cur_addr;
+            let bytes_remaining = self.bytes_remaining();
+
+            match self.bootloader {
                 Bootloader::V1 => {
-                    // Calc packet length
-                    if bytes_to_send >= 60 {
-                        pkt_length = 60;
-                    } else {
-                        pkt_length = bytes_to_send;
+                    // Copy contents
+                    let max_payload = self.definition.max_packet_payload_v1 as usize;
+                    let pkt_length = self.payload_read(&mut packet[4..(max_payload + 4)])?;
+                    if self.complete {
+                        break;
                     }
+                    self.packets_done += 1;
 
                     // Fill header
-                    packet[0] = mode_code;
+                    packet[0] = self.mode_code;
                     packet[1] = (pkt_length & 0xFF) as u8;
                     packet[2] = (cur_addr & 0xFF) as u8;
                     packet[3] = ((cur_addr >> 8) & 0xFF) as u8;
 
-                    // Copy contents
-                    packet[4..(pkt_length + 4)]
-                        .copy_from_slice(&file_buffer[cur_addr..(pkt_length + cur_addr)]);
-
-                    // Send data
-                    let reply = self.request_send(&packet[..])?;
-                    cur_addr += pkt_length;
-                    bytes_to_send -= pkt_length;
-
-                    if reply[0] != 0x00 {
-                        // println!(
-                        //     "ERROR: Error while sending data: Write failed at address 0x{:04X}",
-                        //     cur_addr
-                        // );
-                        return Err(ProtocolError::SerialError);
+                    self.cur_addr += pkt_length;
+                    self.bytes_done += pkt_length;
+
+                    if self.packet_skip(skip_blank, &packet[4..(pkt_length + 4)]) {
+                        continue;
                     }
+
+                    // Packet is always sent with full payload
+                    return Ok(Some(FlashPacket {
+                        data: packet[..max_payload + 4].to_vec(),
+                        address: cur_addr,
+                        length: pkt_length,
+                    }));
                 }
                 Bootloader::V2 => {
-                    // Calc packet length
-                    if bytes_to_send >= 56 {
-                        pkt_length = 56;
-                    } else {
-                        pkt_length = bytes_to_send;
+                    // Copy contents
+                    let max_payload = self.definition.max_packet_payload_v2 as usize;
+                    let payload_length = self.payload_read(&mut packet[8..(max_payload + 8)])?;
+                    if self.complete {
+                        break;
                     }
+                    self.packets_done += 1;
 
-                    // Fill header
-                    packet[0] = mode_code;
-                    packet[1] = ((pkt_length + (pkt_length % 8) + 5) & 0xFF) as u8;
-                    packet[2] = 0x00;
-                    packet[3] = (cur_addr & 0xFF) as u8;
-                    packet[4] = ((cur_addr >> 8) & 0xFF) as u8;
-                    packet[5] = 0x00;
-                    packet[6] = 0x00;
-                    packet[7] = (bytes_to_send & 0xFF) as u8;
+                    // Packet length on 8 bytes boundary
+                    let pkt_length = payload_length + (payload_length % 8);
 
-                    // Copy contents
-                    packet[8..(pkt_length + 8)]
-                        .copy_from_slice(&file_buffer[cur_addr..(pkt_length + cur_addr)]);
+                    // Fill header
+                    let header = V2PacketHeader {
+                        command: self.mode_code,
+                        payload_len: ((pkt_length + 5) & 0xFF) as u8,
+                        reserved: 0x00,
+                        addr_lo: (cur_addr & 0xFF) as u8,
+                        addr_hi: ((cur_addr >> 8) & 0xFF) as u8,
+                        reserved2: 0x00,
+                        reserved3: 0x00,
+                        bytes_remaining: (bytes_remaining.unwrap_or(payload_length) & 0xFF) as u8,
+                    };
+                    packet[..8].copy_from_slice(&header.serialize());
+
+                    // Padding is not a part of the remaining file bytes
+                    self.cur_addr += pkt_length;
+                    self.bytes_done += payload_length;
+                    self.complete = self.bytes_remaining() == Some(0);
+                    if self.complete {
+                        debug!("Complete");
+                    }
 
-                    // Update packet length to make on 8 bytes boundary
-                    pkt_length = pkt_length + (pkt_length % 8);
+                    if self.packet_skip(skip_blank, &packet[8..(payload_length + 8)]) {
+                        continue;
+                    }
 
                     // XOR data with the bootkey
                     for buffer_index in 0..pkt_length {
-                        packet[buffer_index + 8] ^= self.bootkey[buffer_index & 0x07];
+                        packet[buffer_index + 8] ^= bootkey[buffer_index & 0x07];
                     }
 
-                    println!("Processing at address: 0x{:04X}", cur_addr);
-
-                    // Send data
-                    let reply = self.request_send(&packet[..pkt_length + 8])?;
-                    if (reply[4] != 0x00) && (reply[4] != 0xFE) {
-                        // println!(
-                        //     "ERROR: Error while sending data: Failed at address {}",
-                        //     cur_addr
-                        // );
-                        return Err(ProtocolError::SerialError);
-                    }
+                    debug!("Processing at address: 0x{:04X}", cur_addr);
 
-                    cur_addr += pkt_length;
-                    if bytes_to_send >= pkt_length {
-                        bytes_to_send -= pkt_length;
-                    } else {
-                        //println!("Complete!");
-                        return Ok(());
-                    }
+                    return Ok(Some(FlashPacket {
+                        data: packet[..pkt_length + 8].to_vec(),
+                        address: cur_addr,
+                        length: payload_length,
+                    }));
                 }
+                Bootloader::V3 => return Err(ProtocolError::BootloaderNotSupported),
                 Bootloader::Unknown => {
-                    //println!("Unknown bootloader");
+                    debug!("Unknown bootloader");
                     return Err(ProtocolError::BootloaderUnknown);
                 }
             }
         }
 
-        //println!("Writing success");
-        Ok(())
+        Ok(None)
+    }
+
+    /// Firmware bytes already sent
+    pub(crate) fn bytes_done(&self) -> usize {
+        self.bytes_done
+    }
+
+    /// Firmware size, [None] when firmware is read until end of reader
+    pub(crate) fn filesize(&self) -> Option<usize> {
+        self.filesize
+    }
+
+    // Firmware bytes not yet sent, [None] for firmware of unknown size
+    fn bytes_remaining(&self) -> Option<usize> {
+        self.filesize
+            .map(|filesize| filesize.saturating_sub(self.bytes_done))
+    }
+
+    // Copy firmware bytes at current address into *payload*, up to its length,
+    // and return their count. Firmware of unknown size is complete once no bytes are left
+    fn payload_read(&mut self, payload: &mut [u8]) -> Result<usize, ProtocolError> {
+        let read = match self.bytes_remaining() {
+            Some(bytes_remaining) => {
+                let length = bytes_remaining.min(payload.len());
+                self.file_chunk.read_at(self.cur_addr, length)?
+            }
+            None => {
+                let read = self.file_chunk.read_up_to(self.cur_addr, payload.len())?;
+                self.complete = read.is_empty();
+                read
+            }
+        };
+
+        payload[..read.len()].copy_from_slice(read);
+        Ok(read.len())
+    }
+
+    /// Estimated time to send the rest of packets
+    pub(crate) fn time_remaining(&self) -> ProgressEvent {
+        Protocol::time_remaining(self.started, self.packets_done, self.total_packets)
+    }
+
+    // Check if packet with *payload* is not needed to be sent,
+    // blank pages are skipped with *skip_blank* when writing
+    fn packet_skip(&self, skip_blank: bool, payload: &[u8]) -> bool {
+        matches!(self.mode, Mode::Write) && skip_blank && payload.iter().all(|byte| *byte == 0xFF)
+    }
+}
+
+/// Part of firmware file being flashed
+struct FileChunk<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+/// Default size of file chunk kept in memory
+pub(crate) const CHUNK_SIZE: usize = 4096;
+
+/// Smallest file chunk, fits the largest packet payload
+const CHUNK_SIZE_MIN: usize = 64;
+
+impl<R: Read> FileChunk<R> {
+    fn new(reader: R, chunk_size: usize) -> Self {
+        FileChunk {
+            reader,
+            buffer: vec![0; chunk_size.max(CHUNK_SIZE_MIN)],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Get *length* bytes at file address *addr*, reading next chunk when needed.
+    /// Addresses must not decrease between calls
+    fn read_at(&mut self, addr: usize, length: usize) -> Result<&[u8], ProtocolError> {
+        let read = self.read_up_to(addr, length)?;
+        if read.len() < length {
+            // File is shorter than expected
+            return Err(ProtocolError::FileAccessError);
+        }
+
+        Ok(read)
+    }
+
+    /// Get up to *length* bytes at file address *addr*, fewer at the end of file
+    fn read_up_to(&mut self, addr: usize, length: usize) -> Result<&[u8], ProtocolError> {
+        if addr + length > self.start + self.len {
+            // Move not yet sent bytes to the buffer beginning
+            let keep_from = (addr - self.start).min(self.len);
+            self.buffer.copy_within(keep_from..self.len, 0);
+            self.len -= keep_from;
+            self.start += keep_from;
+
+            // Read next chunk
+            while self.len < self.buffer.len() {
+                match self.reader.read(&mut self.buffer[self.len..]) {
+                    Ok(0) => break,
+                    Ok(read_len) => self.len += read_len,
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+
+        let offset = (addr - self.start).min(self.len);
+        let end = (addr + length - self.start).min(self.len);
+        Ok(&self.buffer[offset..end])
+    }
+}
+
+// Placeholder for released serial port, every operation fails
+struct ClosedPort;
+
+impl ClosedPort {
+    fn error() -> serial::Error {
+        serial::Error::new(serial::ErrorKind::NoDevice, "Port is closed")
+    }
+}
+
+impl Read for ClosedPort {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(Self::error().into())
+    }
+}
+
+impl io::Write for ClosedPort {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(Self::error().into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Err(Self::error().into())
+    }
+}
+
+impl SerialPort for ClosedPort {
+    fn timeout(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn set_timeout(&mut self, _timeout: Duration) -> serial::Result<()> {
+        Err(Self::error())
+    }
+
+    fn configure(&mut self, _settings: &serial::PortSettings) -> serial::Result<()> {
+        Err(Self::error())
+    }
+
+    fn reconfigure(
+        &mut self,
+        _setup: &dyn Fn(&mut dyn serial::SerialPortSettings) -> serial::Result<()>,
+    ) -> serial::Result<()> {
+        Err(Self::error())
+    }
+
+    fn set_rts(&mut self, _level: bool) -> serial::Result<()> {
+        Err(Self::error())
+    }
+
+    fn set_dtr(&mut self, _level: bool) -> serial::Result<()> {
+        Err(Self::error())
+    }
+
+    fn read_cts(&mut self) -> serial::Result<bool> {
+        Err(Self::error())
+    }
+
+    fn read_dsr(&mut self) -> serial::Result<bool> {
+        Err(Self::error())
+    }
+
+    fn read_ri(&mut self) -> serial::Result<bool> {
+        Err(Self::error())
+    }
+
+    fn read_cd(&mut self) -> serial::Result<bool> {
+        Err(Self::error())
+    }
+}
+
+/// Preamble starting every request sent to bootloader
+pub const REQUEST_PREAMBLE: [u8; 2] = [0x57, 0xAB];
+
+/// Preamble starting every bootloader reply
+pub const REPLY_PREAMBLE: [u8; 2] = [0x55, 0xAA];
+
+/// Length of request and reply preamble
+pub const PREAMBLE_LEN: usize = 2;
+
+/// Packet checksum, sum of all *payload* bytes wrapping on overflow
+///
+/// Request and reply packets end with checksum of bytes
+/// between the preamble and the checksum byte itself
+pub fn packet_checksum(payload: &[u8]) -> u8 {
+    payload
+        .iter()
+        .fold(0u8, |checksum, byte| checksum.wrapping_add(*byte))
+}
+
+/// Checksum of request *data* following the preamble
+pub(crate) fn compute_request_checksum(data: &[u8]) -> u8 {
+    packet_checksum(data)
+}
+
+/// Checksum of reply *data* between the preamble and the checksum byte
+pub(crate) fn compute_reply_checksum(data: &[u8]) -> u8 {
+    packet_checksum(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockSerialPort, ProtocolSpy};
+
+    // CH552 chip id
+    const CH552: u8 = 0x52;
+
+    // Bootloader reply frame carrying *payload*
+    fn reply(payload: &[u8]) -> Vec<u8> {
+        let mut frame = REPLY_PREAMBLE.to_vec();
+        frame.extend_from_slice(payload);
+        frame.push(packet_checksum(payload));
+        frame
+    }
+
+    // Request frame sent for *payload*
+    fn request(payload: &[u8]) -> Vec<u8> {
+        let mut frame = REQUEST_PREAMBLE.to_vec();
+        frame.extend_from_slice(payload);
+        frame.push(packet_checksum(payload));
+        frame
+    }
+
+    // Protocol talking to a mock port and the port clone scripting it
+    fn mock_protocol() -> (Protocol, MockSerialPort) {
+        let port = MockSerialPort::new();
+        let protocol = Protocol::new(Box::new(port.clone()), "mock");
+        (protocol, port)
+    }
+
+    // Protocol with already detected *bootloader* and CH552 chip
+    fn detected_protocol(bootloader: Bootloader) -> (Protocol, MockSerialPort) {
+        let (mut protocol, port) = mock_protocol();
+        protocol.chip_info = ChipInfo::from((bootloader, CH552));
+        (protocol, port)
+    }
+
+    // V2 config reply with *cfg_word* and *uid*
+    fn config_reply(cfg_word: u32, uid: [u8; 8]) -> [u8; 30] {
+        let mut config = [0; 30];
+        config[..5].copy_from_slice(&[0xA7, 0x00, 0x1A, 0x00, 0x1F]);
+        config[10..14].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+        config[14..18].copy_from_slice(&cfg_word.to_le_bytes());
+        config[19..22].copy_from_slice(&[0x02, 0x04, 0x00]);
+        config[22..30].copy_from_slice(&uid);
+        config
+    }
+
+    #[test]
+    fn packet_checksum_known_values() {
+        assert_eq!(packet_checksum(&[]), 0x00);
+        assert_eq!(packet_checksum(&[0x01, 0x02, 0x03]), 0x06);
+        assert_eq!(packet_checksum(&[0xA5, 0x0D, 0x00, 0x10]), 0xC2);
+    }
+
+    #[test]
+    fn packet_checksum_wraps_on_overflow() {
+        assert_eq!(packet_checksum(&[0xFF, 0x02]), 0x01);
+        assert_eq!(packet_checksum(&[0x80; 4]), 0x00);
+        assert_eq!(packet_checksum(&[0xFF; 256]), 0x00);
+    }
+
+    #[test]
+    fn request_checksum_of_detect_requests() {
+        assert_eq!(
+            compute_request_checksum(SEQUENCES[&Bootloader::V2].chip_detect),
+            0x15
+        );
+        assert_eq!(
+            compute_request_checksum(SEQUENCES[&Bootloader::V1].chip_detect),
+            0x2C
+        );
+        assert_eq!(compute_request_checksum(&[0xA2, 0x01, 0x00, 0x01]), 0xA4);
+    }
+
+    #[test]
+    fn reply_checksum_of_detect_and_erase_replies() {
+        assert_eq!(
+            compute_reply_checksum(&[0xA1, 0x00, 0x02, 0x00, 0x52, 0x11]),
+            0x06
+        );
+        assert_eq!(
+            compute_reply_checksum(&[0xA4, 0x00, 0x02, 0x00, 0x00]),
+            0xA6
+        );
+    }
+
+    #[test]
+    fn bootkey_index_stays_in_random_sequence_for_all_seeds() {
+        let factors = [(7, 4), (5, 1), (7, 1), (7, 6), (7, 3), (5, 3), (7, 5)];
+
+        // Seed sent by key exchange needs no clamping
+        for (divisor, factor) in factors {
+            let offset = (0x30 / divisor) as usize * factor as usize;
+            assert_eq!(Protocol::bootkey_index(0x30, divisor, factor), 3 + offset);
+        }
+
+        for seed in 0..=u8::MAX {
+            for (divisor, factor) in factors {
+                let offset = (seed / divisor).saturating_mul(factor) as usize;
+                if offset < Protocol::BOOTKEY_RANDOM_LEN {
+                    assert_eq!(Protocol::bootkey_index(seed, divisor, factor), 3 + offset);
+                } else if !cfg!(debug_assertions) {
+                    // Clamped to the last random byte, debug builds assert instead
+                    assert_eq!(
+                        Protocol::bootkey_index(seed, divisor, factor),
+                        2 + Protocol::BOOTKEY_RANDOM_LEN
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bootloader_detect_v2() {
+        let (mut protocol, port) = mock_protocol();
+        port.queue_response(reply(&[0xA1, 0x00, 0x02, 0x00, CH552, 0x11]));
+
+        assert_eq!(protocol.bootloader_detect(), Ok(Bootloader::V2));
+        assert_eq!(
+            port.state().sent_packets,
+            [request(SEQUENCES[&Bootloader::V2].chip_detect)]
+        );
+    }
+
+    #[test]
+    fn bootloader_detect_v2_accepts_defined_device_type() {
+        let (mut protocol, port) = mock_protocol();
+        port.queue_response(reply(&[0xA1, 0x00, 0x02, 0x00, 0x3F, 0x15]));
+
+        assert_eq!(protocol.bootloader_detect(), Ok(Bootloader::V2));
+    }
+
+    #[test]
+    fn bootloader_detect_v2_rejects_other_device_type() {
+        let (mut protocol, port) = mock_protocol();
+        port.queue_response(reply(&[0xA1, 0x00, 0x02, 0x00, CH552, 0x15]));
+
+        assert_eq!(
+            protocol.bootloader_detect(),
+            Err(ProtocolError::ForeignWchIsp { device_type: 0x15 })
+        );
+    }
+
+    #[test]
+    fn bootloader_detect_v2_rejects_undefined_ch32_chip() {
+        let (mut protocol, port) = mock_protocol();
+        port.queue_response(reply(&[0xA1, 0x00, 0x02, 0x00, 0x30, 0x17]));
+
+        let err = protocol.bootloader_detect().unwrap_err();
+        assert_eq!(err, ProtocolError::ForeignWchIsp { device_type: 0x17 });
+        assert!(err
+            .to_string()
+            .starts_with("Detected CH32 bootloader — use a CH32-compatible tool"));
+        assert_eq!(protocol.get_chip_info().bootloader, Bootloader::Unknown);
+    }
+
+    #[test]
+    fn bootloader_detect_rejects_v3_chip() {
+        let (mut protocol, port) = mock_protocol();
+        port.queue_response(reply(&[0xA1, 0x00, 0x02, 0x00, 0x69, 0x10]));
+
+        assert_eq!(
+            protocol.bootloader_detect(),
+            Err(ProtocolError::BootloaderNotSupported)
+        );
+        assert_eq!(protocol.get_chip_info().bootloader, Bootloader::Unknown);
+    }
+
+    #[test]
+    fn bootloader_detect_v1() {
+        let (mut protocol, port) = mock_protocol();
+        port.queue_response(reply(&[CH552, 0x11]));
+
+        assert_eq!(protocol.bootloader_detect(), Ok(Bootloader::V1));
+        assert_eq!(protocol.get_chip_info().bootloader, Bootloader::V1);
+    }
+
+    #[test]
+    fn chip_reset_and_detect_ignores_bootloader_detected_before_reset() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+        port.queue_response(reply(&[CH552, 0x11]));
+
+        assert_eq!(protocol.chip_reset_and_detect(), Ok(Bootloader::V1));
+        assert_eq!(
+            port.state().sent_packets,
+            [request(SEQUENCES[&Bootloader::V2].chip_detect)]
+        );
+        assert_eq!(protocol.get_chip_info().chip_id, 0);
+    }
+
+    #[test]
+    fn bootloader_detect_with_timeout_pauses_between_attempts() {
+        let (mut protocol, port) = mock_protocol();
+        protocol.set_max_retries(0);
+
+        assert_eq!(
+            protocol.bootloader_detect_with_timeout(Duration::from_millis(100)),
+            Err(ProtocolError::BootloaderUnknown)
+        );
+        assert!(port.state().sent_packets.len() <= 100 / 20 + 1);
+    }
+
+    #[test]
+    fn chip_detect_v2_reads_config_and_exchanges_key() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+        let uid = [0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0x70, 0x80];
+        port.queue_response(reply(&[0xA1, 0x00, 0x02, 0x00, CH552, 0x11]));
+        port.queue_response(reply(&config_reply(0x4EFF, uid)));
+
+        // Chip derives the key from random bytes of key input request
+        let uid_checksum = packet_checksum(&uid[..4]);
+        port.respond_with(move |frame| {
+            let request = &frame[PREAMBLE_LEN..frame.len() - 1];
+            let seed = request[1];
+            let mut key: Vec<u8> = [(7, 4), (5, 1), (7, 1), (7, 6), (7, 3), (5, 3), (7, 5)]
+                .iter()
+                .map(|&(divisor, factor)| {
+                    request[Protocol::bootkey_index(seed, divisor, factor)] ^ uid_checksum
+                })
+                .collect();
+            key.push(CH552.wrapping_add(key[0]));
+
+            reply(&[0xA3, 0x00, 0x02, 0x00, packet_checksum(&key)])
+        });
+
+        let config = protocol.chip_detect().unwrap().unwrap();
+        assert_eq!(config.cfg_word, 0x4EFF);
+        assert_eq!(config.boot_version, (0x02, 0x04, 0x00));
+        assert_eq!(config.uid, uid);
+        assert_eq!(protocol.get_chip_info().bootloader_version, "2.40");
+        assert_eq!(port.state().sent_packets.len(), 3);
+    }
+
+    #[test]
+    fn chip_detect_v1_identifies_chip() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V1);
+        protocol.chip_info.chip_id = 0;
+        port.queue_response(reply(&[CH552, 0x11]));
+        port.queue_response(reply(&[0x21, 0x03]));
+
+        assert_eq!(protocol.chip_detect(), Ok(None));
+        assert_eq!(protocol.get_chip_info().chip_id, CH552);
+        assert_eq!(protocol.get_chip_info().bootloader_version, "2.3");
+        assert_eq!(
+            port.state().sent_packets,
+            [
+                request(SEQUENCES[&Bootloader::V1].chip_detect),
+                request(SEQUENCES[&Bootloader::V1].config_read),
+            ]
+        );
+    }
+
+    #[test]
+    fn erase_v2_sends_block_count() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+        port.queue_response(reply(&[0xA4, 0x00, 0x02, 0x00, 0x00]));
+
+        assert_eq!(
+            protocol.erase(),
+            Ok(vec![EraseBlockResult {
+                block_index: 0,
+                success: true,
+                error_code: None,
+            }])
+        );
+        assert_eq!(
+            port.state().sent_packets,
+            [request(&[0xA4, 0x01, 0x00, 14])]
+        );
+    }
+
+    #[test]
+    fn erase_rejects_chip_without_erase_blocks() {
+        let (mut protocol, port) = mock_protocol();
+        protocol.chip_info = ChipInfo::from((Bootloader::V2, 0x69));
+
+        assert_eq!(
+            protocol.erase(),
+            Err(ProtocolError::UnsupportedForBootloader {
+                operation: "erase",
+                bootloader: Bootloader::V2,
+            })
+        );
+        assert!(port.state().sent_packets.is_empty());
+    }
+
+    #[test]
+    fn erase_v1_addresses_last_block_of_64() {
+        let (mut protocol, port) = mock_protocol();
+        protocol.chip_info = ChipInfo::from((Bootloader::V1, 0x3F));
+        for _ in 0..3 {
+            port.queue_response(reply(&[0x00]));
+        }
+
+        assert_eq!(protocol.erase_range(62, 2), Ok(()));
+        assert_eq!(
+            port.state().sent_packets[1..],
+            [
+                request(&[0xA9, 0x02, 0x00, 0xF8]),
+                request(&[0xA9, 0x02, 0x00, 0xFC]),
+            ]
+        );
+    }
+
+    #[test]
+    fn write_page_raw_v2_xors_data_with_bootkey() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+        protocol.bootkey = [1, 2, 3, 4, 5, 6, 7, 8];
+        port.queue_response(reply(&[0xA5, 0x00, 0x02, 0x00, 0x00]));
+
+        assert_eq!(protocol.write_page_raw(0x10, &[0xFF; 8]), Ok(()));
+        assert_eq!(
+            port.state().sent_packets,
+            [request(&[
+                0xA5, 13, 0x00, 0x10, 0x00, 0x00, 0x00, 8, 0xFE, 0xFD, 0xFC, 0xFB, 0xFA, 0xF9,
+                0xF8, 0xF7,
+            ])]
+        );
+    }
+
+    #[test]
+    fn flash_bytes_v2_erases_writes_verifies_and_exits() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+        port.queue_response(reply(&[0xA4, 0x00, 0x02, 0x00, 0x00]));
+        for command in [0xA5, 0xA5, 0xA6, 0xA6] {
+            port.queue_response(reply(&[command, 0x00, 0x02, 0x00, 0x00]));
+        }
+        port.queue_response(reply(&[0xA2, 0x00, 0x02, 0x00, 0x00]));
+
+        assert_eq!(protocol.flash_bytes(&[0x02; 64]), Ok(()));
+        assert_eq!(protocol.flash_stats().total_packets, 4);
+
+        let state = port.state();
+        assert_eq!(state.sent_packets.len(), 6);
+        assert_eq!(
+            state.sent_packets[5],
+            request(SEQUENCES[&Bootloader::V2].bootloader_exit)
+        );
+    }
+
+    #[test]
+    fn flash_bytes_rejects_small_firmware_before_erase() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+
+        assert_eq!(
+            protocol.flash_bytes(&[0x02; 16]),
+            Err(ProtocolError::FileTooSmall {
+                firmware_size: 16,
+                minimum: 32,
+            })
+        );
+        assert!(port.state().sent_packets.is_empty());
+    }
+
+    #[test]
+    fn flash_bytes_rejects_firmware_overlapping_bootloader_before_erase() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+
+        assert_eq!(
+            protocol.flash_bytes(&[0x02; 0x3801]),
+            Err(ProtocolError::FirmwareTooLarge {
+                firmware_size: 0x3801,
+                flash_capacity: 0x3800,
+            })
+        );
+        assert!(port.state().sent_packets.is_empty());
+    }
+
+    #[test]
+    fn flash_buffer_v2_last_packet_header_holds_remaining_bytes() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+        for _ in 0..2 {
+            port.queue_response(reply(&[0xA5, 0x00, 0x02, 0x00, 0x00]));
+        }
+
+        assert_eq!(
+            protocol.flash_buffer(&[0x02; 76], Mode::Write, &mut |_| {}),
+            Ok(())
+        );
+
+        // Header follows the preamble, remaining byte count is its last byte
+        let state = port.state();
+        assert_eq!(state.sent_packets.len(), 2);
+        assert_eq!(state.sent_packets[0][PREAMBLE_LEN + 7], 76);
+        assert_eq!(state.sent_packets[1][PREAMBLE_LEN + 3], 56);
+        assert_eq!(state.sent_packets[1][PREAMBLE_LEN + 7], 20);
+    }
+
+    #[test]
+    fn verify_v1_reports_mismatched_block() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V1);
+        for _ in 0..18 {
+            port.queue_response(reply(&[0x00]));
+        }
+        port.queue_response(reply(&[0x05]));
+
+        // Packets of 60 bytes, 19th one is in the second block
+        assert_eq!(
+            protocol.flash_buffer(&[0x02; 2048], Mode::Verify, &mut |_| {}),
+            Err(ProtocolError::VerifyFailedAt {
+                address: 1080,
+                block: 1,
+                reply_code: 0x05,
+            })
+        );
+    }
+
+    #[test]
+    fn verify_v2_accepts_success_and_0xfe_status() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+        port.queue_response(reply(&[0xA6, 0x00, 0x02, 0x00, 0x00]));
+        port.queue_response(reply(&[0xA6, 0x00, 0x02, 0x00, 0xFE]));
+        assert_eq!(
+            protocol.flash_buffer(&[0x02; 64], Mode::Verify, &mut |_| {}),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn verify_v2_reports_other_status_as_unknown() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+        port.queue_response(reply(&[0xA6, 0x00, 0x02, 0x00, 0xF5]));
+
+        assert_eq!(
+            protocol.flash_buffer(&[0x02; 64], Mode::Verify, &mut |_| {}),
+            Err(ProtocolError::VerifyStatusUnknown { code: 0xF5 })
+        );
+        assert_eq!(port.state().sent_packets.len(), 1);
+    }
+
+    #[test]
+    fn verify_bytes_v1_stops_at_mismatch() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V1);
+        port.queue_response(reply(&[CH552, 0x11]));
+        port.queue_response(reply(&[0x21, 0x03]));
+        port.queue_response(reply(&[0x01]));
+
+        assert_eq!(
+            protocol.verify_bytes(&[0x02; 64]),
+            Err(ProtocolError::VerifyFailedAt {
+                address: 0,
+                block: 0,
+                reply_code: 0x01,
+            })
+        );
+        assert_eq!(port.state().sent_packets.len(), 3);
+    }
+
+    #[test]
+    fn verify_streaming_v1_reads_reader_until_end() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V1);
+        port.queue_response(reply(&[CH552, 0x11]));
+        port.queue_response(reply(&[0x21, 0x03]));
+        port.queue_response(reply(&[0x00]));
+        port.queue_response(reply(&[0x00]));
+
+        // Repeated bytes cannot be sought, firmware size is not known in advance
+        assert_eq!(
+            protocol.verify_streaming(io::repeat(0x02).take(100), CHUNK_SIZE_MIN),
+            Ok(())
+        );
+
+        // Packets of 60 and 40 bytes after chip detect requests
+        let state = port.state();
+        assert_eq!(state.sent_packets.len(), 4);
+        assert_eq!(state.sent_packets[2][PREAMBLE_LEN + 1], 60);
+        assert_eq!(state.sent_packets[3][PREAMBLE_LEN + 1], 40);
+        assert_eq!(state.sent_packets[3][PREAMBLE_LEN + 2], 60);
+    }
+
+    #[test]
+    fn flash_stream_v2_of_unknown_size_reports_payload_as_remaining() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+        port.queue_response(reply(&[0xA6, 0x00, 0x02, 0x00, 0x00]));
+        port.queue_response(reply(&[0xA6, 0x00, 0x02, 0x00, 0x00]));
+
+        assert_eq!(
+            protocol.flash_stream(
+                io::repeat(0x02).take(100),
+                None,
+                CHUNK_SIZE_MIN,
+                Mode::Verify,
+                &mut |_| {}
+            ),
+            Ok(())
+        );
+
+        // Packets of 56 and 44 bytes, remaining bytes are not known
+        let state = port.state();
+        assert_eq!(state.sent_packets.len(), 2);
+        assert_eq!(state.sent_packets[0][PREAMBLE_LEN + 7], 56);
+        assert_eq!(state.sent_packets[1][PREAMBLE_LEN + 3], 56);
+        assert_eq!(state.sent_packets[1][PREAMBLE_LEN + 7], 44);
+    }
+
+    #[test]
+    fn request_send_without_retries_sends_once() {
+        let (mut protocol, port) = mock_protocol();
+        protocol.set_max_retries(0);
+
+        assert_eq!(
+            protocol.request_send(&[0xA2, 0x01, 0x00, 0x01]),
+            Err(ProtocolError::SerialError {
+                port: "mock".to_string(),
+                kind: SerialErrorKind::Timeout,
+            })
+        );
+        assert_eq!(port.state().sent_packets.len(), 1);
+        assert_eq!(protocol.flash_stats().packets_retried, 0);
+    }
+
+    #[test]
+    fn request_send_retries_after_timeout() {
+        let (mut protocol, port) = mock_protocol();
+        protocol.set_max_retries(2);
+
+        assert!(protocol.request_send(&[0xA2, 0x01, 0x00, 0x01]).is_err());
+        assert_eq!(port.state().sent_packets.len(), 3);
+        assert_eq!(protocol.flash_stats().packets_retried, 2);
+
+        // Reply to a resent request is accepted
+        port.state().sent_packets.clear();
+        port.queue_response(Vec::new());
+        port.queue_response(reply(&[0xA2, 0x00, 0x02, 0x00, 0x00]));
+        assert_eq!(
+            protocol.request_send(&[0xA2, 0x01, 0x00, 0x01]),
+            Ok(vec![0xA2, 0x00, 0x02, 0x00, 0x00])
+        );
+        assert_eq!(port.state().sent_packets.len(), 2);
+    }
+
+    #[test]
+    fn request_send_reply_outlives_protocol_borrows() {
+        let (mut protocol, port) = mock_protocol();
+        port.queue_response(reply(&[0xA1, 0x00, 0x02, 0x00, CH552, 0x11]));
+        port.queue_response(reply(&[0xA2, 0x00, 0x02, 0x00, 0x00]));
+
+        let detect_reply = protocol
+            .request_send(SEQUENCES[&Bootloader::V2].chip_detect)
+            .unwrap();
+
+        // Reply is owned, shared and mutable borrows of protocol are allowed while it is held
+        assert_eq!(protocol.get_chip_info().chip_id, 0);
+        let exit_reply = protocol
+            .request_send(SEQUENCES[&Bootloader::V2].bootloader_exit)
+            .unwrap();
+
+        assert_eq!(detect_reply, [0xA1, 0x00, 0x02, 0x00, CH552, 0x11]);
+        assert_eq!(exit_reply, [0xA2, 0x00, 0x02, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn request_send_checksum_mismatch() {
+        let (mut protocol, port) = mock_protocol();
+        protocol.set_max_retries(0);
+        let mut frame = reply(&[0xA1, 0x00, 0x02, 0x00, CH552, 0x11]);
+        *frame.last_mut().unwrap() ^= 0xFF;
+        port.queue_response(frame);
+
+        assert_eq!(
+            protocol.request_send(SEQUENCES[&Bootloader::V2].chip_detect),
+            Err(ProtocolError::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn request_send_preamble_mismatch() {
+        let (mut protocol, port) = mock_protocol();
+        protocol.set_max_retries(0);
+        port.queue_response([0xAA, 0x55, 0x00, 0x00]);
+
+        assert_eq!(
+            protocol.request_send(SEQUENCES[&Bootloader::V2].chip_detect),
+            Err(ProtocolError::PreableMismatch)
+        );
+    }
+
+    #[test]
+    fn write_page_raw_v2_failed_status() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+        port.queue_response(reply(&[0xA5, 0x00, 0x02, 0x00, 0x01]));
+
+        assert_eq!(
+            protocol.write_page_raw(0x40, &[0x02; 8]),
+            Err(ProtocolError::WriteFailedAt {
+                address: 0x40,
+                reply_code: 0x01,
+            })
+        );
+    }
+
+    #[test]
+    fn write_page_raw_rejects_misaligned_address() {
+        let (mut protocol, port) = detected_protocol(Bootloader::V2);
+
+        assert_eq!(
+            protocol.write_page_raw(0x41, &[0x02; 8]),
+            Err(ProtocolError::MisalignedAddress { addr: 0x41 })
+        );
+        assert!(port.state().sent_packets.is_empty());
+    }
+
+    #[test]
+    fn io_errors_compare_by_kind() {
+        let not_found = ProtocolError::from(io::Error::new(io::ErrorKind::NotFound, "first"));
+
+        assert_eq!(
+            not_found,
+            ProtocolError::from(io::Error::new(io::ErrorKind::NotFound, "second"))
+        );
+        assert_ne!(
+            not_found,
+            ProtocolError::from(io::Error::new(io::ErrorKind::TimedOut, "first"))
+        );
+        assert_eq!(not_found.clone(), not_found);
+    }
+
+    #[test]
+    fn request_log_keeps_errors() {
+        let (protocol, port) = mock_protocol();
+        let mut spy = ProtocolSpy::new(protocol);
+        spy.set_max_retries(0);
+
+        assert_eq!(
+            spy.bootloader_detect(),
+            Err(ProtocolError::BootloaderUnknown)
+        );
+        assert_eq!(
+            spy.log(),
+            [(
+                SEQUENCES[&Bootloader::V2].chip_detect.to_vec(),
+                Err(ProtocolError::SerialError {
+                    port: "mock".to_string(),
+                    kind: SerialErrorKind::Timeout,
+                }),
+            )]
+        );
+        assert_eq!(port.state().sent_packets.len(), 1);
     }
 }
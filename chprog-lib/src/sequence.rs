@@ -3,16 +3,60 @@
 //! Bootloader versions have different byte sequences for managing microcontroller
 
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use thiserror::Error;
 
-#[derive(Clone, Copy, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq)]
 pub enum Bootloader {
     Unknown,
     V1,
     V2,
+    /// Not supported yet, reserved for newer WCH ISP bootloaders,
+    /// its [SEQUENCES] entry is an empty placeholder
+    V3,
 }
 
 impl Eq for Bootloader {}
 
+/// Error of parsing bootloader version from string
+#[derive(Error, Debug)]
+#[error("Unknown bootloader version: {0}")]
+pub struct UnknownBootloaderStr(pub String);
+
+impl Bootloader {
+    /// Parse bootloader version like "v1", "V1" or "1"
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<Bootloader> {
+        match s {
+            "v1" | "V1" | "1" => Some(Bootloader::V1),
+            "v2" | "V2" | "2" => Some(Bootloader::V2),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Bootloader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Bootloader::Unknown => "Unknown",
+            Bootloader::V1 => "V1",
+            Bootloader::V2 => "V2",
+            Bootloader::V3 => "V3",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Bootloader {
+    type Err = UnknownBootloaderStr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Bootloader::from_str(s).ok_or_else(|| UnknownBootloaderStr(s.to_string()))
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Sequence {
     pub chip_detect: &'static [u8],
@@ -22,45 +66,120 @@ pub struct Sequence {
     pub mode_verify: &'static [u8],
     pub config_read: &'static [u8],
     pub config_write: &'static [u8],
+    /// Data flash (EEPROM) read command, empty if not supported
+    pub data_flash_read: &'static [u8],
+    /// Data flash (EEPROM) write command, empty if not supported
+    pub data_flash_write: &'static [u8],
+    /// Data flash (EEPROM) erase command, empty if not supported
+    pub data_flash_erase: &'static [u8],
+    /// Reply status code of an accepted request
+    pub success_code: u8,
+    /// Delay between sending a request and reading its reply
+    pub response_delay_ms: u64,
 }
 
-lazy_static! {
-    pub static ref SEQUENCES: HashMap<Bootloader, Sequence> = [
-        (
-            Bootloader::V1,
-            Sequence {
-                chip_detect: &[
-                    0xA2, 0x13, 0x55, 0x53, 0x42, 0x20, 0x44, 0x42, 0x47, 0x20, 0x43, 0x48, 0x35,
-                    0x35, 0x39, 0x20, 0x26, 0x20, 0x49, 0x53, 0x50, 0x00
-                ],
-                bootloader_exit: &[0xA5, 0x02, 0x01, 0x00],
-                flash_erase: &[0xA6, 0x04, 0x00, 0x00, 0x00, 0x00],
-                mode_write: &[0xA8],
-                mode_verify: &[0xA7],
-                config_read: &[0xBB, 0x00],
-                config_write: &[],
-            }
-        ),
-        (
-            Bootloader::V2,
-            Sequence {
-                chip_detect: &[
-                    0xA1, 0x12, 0x00, 0x59, 0x11, 0x4D, 0x43, 0x55, 0x20, 0x49, 0x53, 0x50, 0x20,
-                    0x26, 0x20, 0x57, 0x43, 0x48, 0x2E, 0x43, 0x4E
-                ],
-                bootloader_exit: &[0xA2, 0x01, 0x00, 0x01],
-                flash_erase: &[0xA4, 0x01, 0x00, 0x00],
-                mode_write: &[0xA5],
-                mode_verify: &[0xA6],
-                config_read: &[0xA7, 0x02, 0x00, 0x1F, 0x00],
-                config_write: &[
-                    0xA8, 0x0E, 0x00, 0x07, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x03, 0x00, 0x00, 0x00,
-                    0xFF, 0x4E, 0x00, 0x00
-                ],
-            }
-        ),
+/// Check that commands required for flashing are present in *sequence*
+pub const fn validate_sequence(sequence: &Sequence) -> bool {
+    !(sequence.chip_detect.is_empty()
+        || sequence.bootloader_exit.is_empty()
+        || sequence.flash_erase.is_empty()
+        || sequence.mode_write.is_empty()
+        || sequence.mode_verify.is_empty())
+}
+
+const SEQUENCE_V1: Sequence = Sequence {
+    chip_detect: &[
+        0xA2, 0x13, 0x55, 0x53, 0x42, 0x20, 0x44, 0x42, 0x47, 0x20, 0x43, 0x48, 0x35, 0x35, 0x39,
+        0x20, 0x26, 0x20, 0x49, 0x53, 0x50, 0x00,
+    ],
+    bootloader_exit: &[0xA5, 0x02, 0x01, 0x00],
+    flash_erase: &[0xA6, 0x04, 0x00, 0x00, 0x00, 0x00],
+    mode_write: &[0xA8],
+    mode_verify: &[0xA7],
+    config_read: &[0xBB, 0x00],
+    config_write: &[],
+    data_flash_read: &[],
+    data_flash_write: &[],
+    data_flash_erase: &[],
+    success_code: 0x00,
+    response_delay_ms: 10,
+};
+
+const SEQUENCE_V2: Sequence = Sequence {
+    chip_detect: &[
+        0xA1, 0x12, 0x00, 0x59, 0x11, 0x4D, 0x43, 0x55, 0x20, 0x49, 0x53, 0x50, 0x20, 0x26, 0x20,
+        0x57, 0x43, 0x48, 0x2E, 0x43, 0x4E,
+    ],
+    bootloader_exit: &[0xA2, 0x01, 0x00, 0x01],
+    flash_erase: &[0xA4, 0x01, 0x00, 0x00],
+    mode_write: &[0xA5],
+    mode_verify: &[0xA6],
+    config_read: &[0xA7, 0x02, 0x00, 0x1F, 0x00],
+    config_write: &[
+        0xA8, 0x0E, 0x00, 0x07, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x03, 0x00, 0x00, 0x00, 0xFF, 0x4E,
+        0x00, 0x00,
+    ],
+    data_flash_read: &[],
+    data_flash_write: &[],
+    data_flash_erase: &[],
+    success_code: 0x00,
+    response_delay_ms: 0,
+};
+
+/// Placeholder for V3 bootloader commands, operations with V3 bootloader are not supported
+const SEQUENCE_V3: Sequence = Sequence {
+    chip_detect: &[],
+    bootloader_exit: &[],
+    flash_erase: &[],
+    mode_write: &[],
+    mode_verify: &[],
+    config_read: &[],
+    config_write: &[],
+    data_flash_read: &[],
+    data_flash_write: &[],
+    data_flash_erase: &[],
+    success_code: 0x00,
+    response_delay_ms: 0,
+};
+
+// Built-in sequences must be complete, V3 placeholder must not pass for one
+const _: () = assert!(validate_sequence(&SEQUENCE_V1));
+const _: () = assert!(validate_sequence(&SEQUENCE_V2));
+const _: () = assert!(!validate_sequence(&SEQUENCE_V3));
+
+pub static SEQUENCES: LazyLock<HashMap<Bootloader, Sequence>> = LazyLock::new(|| {
+    [
+        (Bootloader::V1, SEQUENCE_V1),
+        (Bootloader::V2, SEQUENCE_V2),
+        (Bootloader::V3, SEQUENCE_V3),
     ]
     .iter()
     .copied()
-    .collect();
+    .collect()
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequences_populated_on_first_access() {
+        assert_eq!(SEQUENCES[&Bootloader::V1].mode_write, [0xA8]);
+        assert_eq!(SEQUENCES[&Bootloader::V2].mode_write, [0xA5]);
+        assert!(!SEQUENCES.contains_key(&Bootloader::Unknown));
+    }
+
+    #[test]
+    fn all_sequences_are_complete() {
+        for (bootloader, sequence) in SEQUENCES.iter() {
+            // V3 entry is a placeholder only
+            assert_eq!(
+                validate_sequence(sequence),
+                *bootloader != Bootloader::V3,
+                "{} sequence",
+                bootloader
+            );
+        }
+        assert!(SEQUENCES[&Bootloader::V3].chip_detect.is_empty());
+    }
 }
@@ -0,0 +1,61 @@
+//! ChProg serial file
+//!
+//! Lookup of serial ports with a connected microcontroller
+
+use super::definitions::ChipFamily;
+use super::ChProg;
+use serialport::SerialPortType;
+
+/// USB-UART adapters (VID, PID) commonly used with CH55x
+const KNOWN_ADAPTERS: [(u16, u16); 5] = [
+    (0x1A86, 0x7523), // CH340
+    (0x1A86, 0x5523), // CH341
+    (0x10C4, 0xEA60), // CP2102
+    (0x0403, 0x6001), // FT232R
+    (0x067B, 0x2303), // PL2303
+];
+
+/// Lists serial ports of known USB-UART adapters and pings each of them
+///
+/// Returns names of ports with a chip answering in bootloader mode paired with its family,
+/// [ChipFamily::Unknown] if bootloader replies but chip is not identified
+pub fn list_ch55x_ports() -> Vec<(String, ChipFamily)> {
+    let mut ports = Vec::new();
+
+    let available_ports = match serialport::available_ports() {
+        Ok(available_ports) => available_ports,
+        Err(_) => return ports,
+    };
+
+    for port_info in available_ports {
+        // Filter known adapters, other ports are not pinged
+        let candidate = match port_info.port_type {
+            SerialPortType::UsbPort(usb_info) => {
+                KNOWN_ADAPTERS.contains(&(usb_info.vid, usb_info.pid))
+            }
+            _ => false,
+        };
+
+        if !candidate {
+            continue;
+        }
+
+        // Ping chip
+        let Ok(mut chprog) = ChProg::new(&port_info.port_name) else {
+            continue;
+        };
+
+        if chprog.protocol.bootloader_detect().is_err() {
+            continue;
+        }
+
+        // Bootloader replied, chip may still be unidentified
+        let chip_family = match chprog.protocol.chip_detect() {
+            Ok(_) => ChipFamily::from(chprog.protocol.chip_id()),
+            Err(_) => ChipFamily::Unknown,
+        };
+        ports.push((port_info.port_name, chip_family));
+    }
+
+    ports
+}
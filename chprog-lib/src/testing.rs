@@ -0,0 +1,227 @@
+//! ChProg testing file
+//!
+//! Helpers for checking protocol exchange in tests
+
+use super::protocol::{Protocol, ProtocolError};
+use serial::prelude::*;
+use std::fmt;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Request sent to MCU and its reply or error
+pub type RequestLogEntry = (Vec<u8>, Result<Vec<u8>, ProtocolError>);
+
+/// [Protocol] wrapper logging every request sent to MCU
+pub struct ProtocolSpy {
+    inner: Protocol,
+}
+
+impl ProtocolSpy {
+    /// Wrap *inner* protocol and start logging its requests
+    pub fn new(mut inner: Protocol) -> Self {
+        inner.request_log = Some(Vec::new());
+        ProtocolSpy { inner }
+    }
+
+    /// Requests sent so far in order
+    pub fn log(&self) -> &[RequestLogEntry] {
+        self.inner.request_log.as_deref().unwrap_or_default()
+    }
+
+    /// Stop logging and return wrapped protocol
+    pub fn into_inner(mut self) -> Protocol {
+        self.inner.request_log = None;
+        self.inner
+    }
+}
+
+impl Deref for ProtocolSpy {
+    type Target = Protocol;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for ProtocolSpy {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+/// Reply built from a written packet, for replies depending on request contents
+pub type MockResponder = Box<dyn FnMut(&[u8]) -> Vec<u8> + Send>;
+
+/// Exchange state shared by clones of [MockSerialPort]
+pub struct MockSerialState {
+    /// Replies sent back in order, one for each written packet
+    pub queued_responses: Vec<Vec<u8>>,
+    /// Packets written to port in order
+    pub sent_packets: Vec<Vec<u8>>,
+    /// Reply to a packet written when no response is queued
+    pub responder: Option<MockResponder>,
+    /// Read timeout set on port
+    pub timeout: Duration,
+    /// Settings configured on port
+    pub settings: serial::PortSettings,
+    // Rest of the reply to the last written packet
+    pending: Vec<u8>,
+}
+
+impl Default for MockSerialState {
+    fn default() -> Self {
+        MockSerialState {
+            queued_responses: Vec::new(),
+            sent_packets: Vec::new(),
+            responder: None,
+            timeout: Protocol::DEFAULT_TIMEOUT,
+            settings: serial::PortSettings {
+                baud_rate: serial::Baud57600,
+                char_size: serial::Bits8,
+                parity: serial::ParityNone,
+                stop_bits: serial::Stop1,
+                flow_control: serial::FlowNone,
+            },
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Debug for MockSerialState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockSerialState")
+            .field("queued_responses", &self.queued_responses)
+            .field("sent_packets", &self.sent_packets)
+            .field("timeout", &self.timeout)
+            .field("pending", &self.pending)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Serial port replaying queued responses instead of a connected MCU
+///
+/// Clones share the same state, so one clone can be passed to [Protocol::new]
+/// and another one used to queue replies and check sent packets.
+/// Reading beyond the current reply fails with timeout like a silent chip
+#[derive(Clone)]
+pub struct MockSerialPort {
+    state: Arc<Mutex<MockSerialState>>,
+}
+
+impl MockSerialPort {
+    /// Create port without queued responses
+    pub fn new() -> Self {
+        MockSerialPort {
+            state: Arc::new(Mutex::new(MockSerialState::default())),
+        }
+    }
+
+    /// Queue *response* to be replied to the next packet without reply
+    pub fn queue_response(&self, response: impl Into<Vec<u8>>) {
+        self.state().queued_responses.push(response.into());
+    }
+
+    /// Reply to packets written after queued responses run out with *responder*
+    pub fn respond_with(&self, responder: impl FnMut(&[u8]) -> Vec<u8> + Send + 'static) {
+        self.state().responder = Some(Box::new(responder));
+    }
+
+    /// Shared exchange state
+    pub fn state(&self) -> MutexGuard<'_, MockSerialState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Default for MockSerialPort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl io::Read for MockSerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        if state.pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
+
+        let length = buf.len().min(state.pending.len());
+        buf[..length].copy_from_slice(&state.pending[..length]);
+        state.pending.drain(..length);
+
+        Ok(length)
+    }
+}
+
+impl io::Write for MockSerialPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut guard = self.state();
+        let state = &mut *guard;
+        state.sent_packets.push(buf.to_vec());
+
+        // Chip replies to each packet
+        if !state.queued_responses.is_empty() {
+            state.pending = state.queued_responses.remove(0);
+        } else if let Some(responder) = &mut state.responder {
+            state.pending = responder(buf);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for MockSerialPort {
+    fn timeout(&self) -> Duration {
+        self.state().timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serial::Result<()> {
+        self.state().timeout = timeout;
+        Ok(())
+    }
+
+    fn configure(&mut self, settings: &serial::PortSettings) -> serial::Result<()> {
+        self.state().settings = *settings;
+        Ok(())
+    }
+
+    fn reconfigure(
+        &mut self,
+        setup: &dyn Fn(&mut dyn serial::SerialPortSettings) -> serial::Result<()>,
+    ) -> serial::Result<()> {
+        setup(&mut self.state().settings)
+    }
+
+    fn set_rts(&mut self, _level: bool) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_dtr(&mut self, _level: bool) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn read_cts(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_dsr(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_ri(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_cd(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+}
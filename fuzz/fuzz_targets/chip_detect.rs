@@ -0,0 +1,34 @@
+//! Chip detection with arbitrary bootloader replies
+//!
+//! Input is split into length prefixed reply payloads framed with valid
+//! preamble and checksum, so detect, config and key exchange replies reach parsing
+
+#![no_main]
+
+use chprog_lib::protocol::{packet_checksum, Protocol, REPLY_PREAMBLE};
+use chprog_lib::testing::MockSerialPort;
+use libfuzzer_sys::fuzz_target;
+
+// Reply frame carrying *payload*
+fn reply(payload: &[u8]) -> Vec<u8> {
+    let mut frame = REPLY_PREAMBLE.to_vec();
+    frame.extend_from_slice(payload);
+    frame.push(packet_checksum(payload));
+    frame
+}
+
+fuzz_target!(|data: &[u8]| {
+    let port = MockSerialPort::new();
+    let mut rest = data;
+    while let Some((&length, tail)) = rest.split_first() {
+        let length = (length as usize).min(tail.len());
+        port.queue_response(reply(&tail[..length]));
+        rest = &tail[length..];
+    }
+
+    let mut protocol = Protocol::new(Box::new(port), "fuzz");
+    protocol.set_max_retries(0);
+    if protocol.bootloader_detect().is_ok() {
+        protocol.chip_detect().ok();
+    }
+});
@@ -0,0 +1,14 @@
+//! Intel HEX parsing and validation of arbitrary input
+
+#![no_main]
+
+use chprog_lib::{format, ihex};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    format::parse_ihex(data).ok();
+
+    if let Ok(text) = std::str::from_utf8(data) {
+        ihex::validate(text).ok();
+    }
+});